@@ -0,0 +1,252 @@
+//! benchmarking for the tree
+//! should be started with:
+//! ```bash
+//! cargo +nightly bench
+//! ```
+//!
+//! Single operations are measured on trees of 1k, 100k and 1M keys. Trees
+//! are committed first, so only cold reads go to the database. Proofs are
+//! generated from and verified against committed trees.
+
+#![feature(test)]
+
+extern crate test;
+extern crate rand;
+extern crate merkle_patricia_tree;
+
+use test::Bencher;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use merkle_patricia_tree::tree::{MerkleTree, H256, EMPTY_ROOT};
+use merkle_patricia_tree::db::Database;
+use merkle_patricia_tree::proof::verify_proof;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// LevelDB store in a temp directory that is removed when dropped
+struct TempDb {
+    path: PathBuf,
+    db: Arc<Database>,
+}
+
+impl TempDb {
+    fn new(name: &str) -> TempDb {
+        let path = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&path);
+        let db = Database::new(path.to_str().unwrap()).unwrap();
+        TempDb {path, db}
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn random_keys(rng: &mut XorShiftRng, count: usize) -> Vec<H256> {
+    (0..count).map(|_| H256::from(rng.gen::<[u8; 32]>())).collect()
+}
+
+// Committed tree with `count` random keys, and the keys
+fn build_tree(db: Arc<Database>, count: usize) -> (MerkleTree<u64>, Vec<H256>) {
+    let mut rng = XorShiftRng::new_unseeded();
+    let keys = random_keys(&mut rng, count);
+    let mut tree = MerkleTree::new(EMPTY_ROOT, db);
+
+    tree.update_batch(keys.iter().enumerate().map(|(index, key)| (*key, Some(index as u64)))).unwrap();
+    tree.commit();
+    (tree, keys)
+}
+
+fn bench_insert(b: &mut Bencher, count: usize) {
+    let (tree, _) = build_tree(Database::in_memory(), count);
+    // seeded differently from `build_tree`, so the keys are new
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let new_keys = random_keys(&mut rng, 1000);
+    let mut index = 0;
+
+    // every insert goes into a fresh clone, so the tree keeps its size
+    b.iter(|| {
+        index = (index + 1) % new_keys.len();
+        let mut tree = tree.clone();
+        tree.update(&new_keys[index], Some(0)).unwrap();
+        tree
+    });
+}
+
+fn bench_get(b: &mut Bencher, count: usize) {
+    let (tree, keys) = build_tree(Database::in_memory(), count);
+    let mut index = 0;
+
+    b.iter(|| {
+        index = (index + 1) % keys.len();
+        tree.get(&keys[index]).unwrap()
+    });
+}
+
+fn bench_delete(b: &mut Bencher, count: usize) {
+    let (tree, keys) = build_tree(Database::in_memory(), count);
+    let mut index = 0;
+
+    b.iter(|| {
+        index = (index + 1) % keys.len();
+        let mut tree = tree.clone();
+        tree.update(&keys[index], None).unwrap();
+        tree
+    });
+}
+
+// Lookups through a tree opened at the root, every node comes from `db`
+fn bench_cold_get(b: &mut Bencher, db: Arc<Database>, count: usize) {
+    let (tree, keys) = build_tree(db.clone(), count);
+    let root = tree.root_hash();
+    let mut index = 0;
+
+    b.iter(|| {
+        index = (index + 1) % keys.len();
+        let tree = MerkleTree::<u64>::at_root(db.clone(), root).unwrap();
+        tree.get(&keys[index]).unwrap()
+    });
+}
+
+// Tree with `count` random keys that are all still uncommitted
+fn dirty_tree(db: Arc<Database>, count: usize) -> MerkleTree<u64> {
+    let mut rng = XorShiftRng::new_unseeded();
+    let mut tree = MerkleTree::new(EMPTY_ROOT, db);
+
+    for (index, key) in random_keys(&mut rng, count).into_iter().enumerate() {
+        tree.update(&key, Some(index as u64)).unwrap();
+    }
+    tree
+}
+
+#[bench]
+fn bench_insert_1k(b: &mut Bencher) {
+    bench_insert(b, 1_000);
+}
+
+#[bench]
+fn bench_insert_100k(b: &mut Bencher) {
+    bench_insert(b, 100_000);
+}
+
+#[bench]
+fn bench_insert_1m(b: &mut Bencher) {
+    bench_insert(b, 1_000_000);
+}
+
+#[bench]
+fn bench_get_1k(b: &mut Bencher) {
+    bench_get(b, 1_000);
+}
+
+#[bench]
+fn bench_get_100k(b: &mut Bencher) {
+    bench_get(b, 100_000);
+}
+
+#[bench]
+fn bench_get_1m(b: &mut Bencher) {
+    bench_get(b, 1_000_000);
+}
+
+#[bench]
+fn bench_delete_1k(b: &mut Bencher) {
+    bench_delete(b, 1_000);
+}
+
+#[bench]
+fn bench_delete_100k(b: &mut Bencher) {
+    bench_delete(b, 100_000);
+}
+
+#[bench]
+fn bench_delete_1m(b: &mut Bencher) {
+    bench_delete(b, 1_000_000);
+}
+
+#[bench]
+fn bench_cold_get_100k_memory(b: &mut Bencher) {
+    bench_cold_get(b, Database::in_memory(), 100_000);
+}
+
+#[bench]
+fn bench_cold_get_100k_leveldb(b: &mut Bencher) {
+    let temp = TempDb::new("mpt_bench_cold_get_100k");
+    bench_cold_get(b, temp.db.clone(), 100_000);
+}
+
+#[bench]
+fn bench_cold_get_1m_leveldb(b: &mut Bencher) {
+    let temp = TempDb::new("mpt_bench_cold_get_1m");
+    bench_cold_get(b, temp.db.clone(), 1_000_000);
+}
+
+#[bench]
+fn bench_update_batch_1k(b: &mut Bencher) {
+    let (tree, _) = build_tree(Database::in_memory(), 100_000);
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let changes: Vec<_> = random_keys(&mut rng, 1000).into_iter().map(|key| (key, Some(0))).collect();
+
+    b.iter(|| {
+        let mut tree = tree.clone();
+        tree.update_batch(changes.clone()).unwrap();
+        tree
+    });
+}
+
+#[bench]
+fn bench_commit_1k(b: &mut Bencher) {
+    let tree = dirty_tree(Database::in_memory(), 1_000);
+
+    // every round commits a fresh clone, so all nodes are hashed again
+    b.iter(|| tree.clone().commit());
+}
+
+#[bench]
+fn bench_commit_100k(b: &mut Bencher) {
+    let tree = dirty_tree(Database::in_memory(), 100_000);
+
+    b.iter(|| tree.clone().commit());
+}
+
+#[bench]
+fn bench_commit_100k_leveldb(b: &mut Bencher) {
+    let temp = TempDb::new("mpt_bench_commit_100k");
+    let tree = dirty_tree(temp.db.clone(), 100_000);
+
+    b.iter(|| tree.clone().commit());
+}
+
+#[bench]
+fn bench_commit_parallel_100k(b: &mut Bencher) {
+    let tree = dirty_tree(Database::in_memory(), 100_000);
+
+    b.iter(|| tree.clone().commit_parallel(4));
+}
+
+#[bench]
+fn bench_get_proof_100k(b: &mut Bencher) {
+    let (tree, keys) = build_tree(Database::in_memory(), 100_000);
+    let mut index = 0;
+
+    b.iter(|| {
+        index = (index + 1) % keys.len();
+        tree.get_proof(&keys[index]).unwrap()
+    });
+}
+
+#[bench]
+fn bench_verify_proof_100k(b: &mut Bencher) {
+    let (tree, keys) = build_tree(Database::in_memory(), 100_000);
+    let root = tree.root_hash();
+    let proofs: Vec<_> = keys.iter().take(1000).map(|key| tree.get_proof(key).unwrap()).collect();
+    let mut index = 0;
+
+    b.iter(|| {
+        index = (index + 1) % proofs.len();
+        verify_proof::<u64>(&root, &keys[index], &proofs[index]).unwrap()
+    });
+}
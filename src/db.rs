@@ -1,88 +1,877 @@
 use exonum_leveldb::database;
 use exonum_leveldb::kv::KV;
+use exonum_leveldb::batch::{Batch, Writebatch};
+use exonum_leveldb::iterator::Iterable;
 use exonum_leveldb::options::{Options, WriteOptions, ReadOptions};
 use ethereum_types::H256;
 use rlp::{Encodable, Decodable};
 use rlp;
 use node::*;
+use error::SchemaError;
 use std::clone::Clone;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+/// Separate keyspaces in one database. Keys are stored behind the prefix
+/// byte of their column, so subsystems sharing a database can use the same
+/// keys without collisions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Column {
+    /// Trie nodes by hash
+    Nodes,
+    /// Reference counts of the nodes by node hash
+    RefCounts,
+    /// Contract code by hash
+    Code,
+    /// Keys of secure trees by their hash
+    Preimages,
+    /// Named values, e.g. roots and the schema version
+    Meta,
+}
+
+impl Column {
+    fn prefix(&self) -> u8 {
+        match *self {
+            Column::Nodes => 0,
+            Column::RefCounts => 1,
+            Column::Code => 2,
+            Column::Preimages => 3,
+            Column::Meta => 4,
+        }
+    }
+
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(key.len() + 1);
+        result.push(self.prefix());
+        result.extend_from_slice(key);
+        result
+    }
+}
+
+/// Version of the key layout, stored in new databases. Opening a database
+/// with another version fails, as does opening one without a version that
+/// isn't empty, see `SchemaError`.
+pub const SCHEMA_VERSION: u32 = 1;
+/// Name of the most recent root, see `MerkleTree::open_latest`
+pub const LATEST_ROOT: &str = "latest";
+/// Name of the root that can't be reverted anymore
+pub const FINALIZED_ROOT: &str = "finalized";
+
+// Keys of the metadata column
+const SCHEMA_VERSION_KEY: &[u8] = b"schema-version";
+const NAMED_ROOT_PREFIX: &[u8] = b"root:";
+const BLOCK_ROOT_PREFIX: &[u8] = b"block-root:";
+const ROOT_BLOCK_PREFIX: &[u8] = b"root-block:";
+
+/// What a commit or a prune did: applying it with `Database::undo` takes
+/// back the root reference the write added or released, and with it the
+/// nodes only that reference kept. Counts are changed relative to the
+/// current ones, so changes other writes made since are kept.
+#[derive(Default)]
+pub struct UndoLog {
+    // root whose reference a commit added
+    inserted: Option<H256>,
+    // root whose reference a prune released, with the nodes it deleted,
+    // parents before their children
+    pruned: Option<PrunedRoot>,
+}
+
+// Root a prune released and the nodes it deleted
+type PrunedRoot = (H256, Vec<(H256, Vec<u8>)>);
+
+// Where the values live
+enum Backend {
+    LevelDb(database::Database),
+    // ordered like LevelDB, so both iterate in the same order
+    Memory(RwLock<BTreeMap<Vec<u8>, Vec<u8>>>),
+}
 
+// Puts and deletes that are written together
+#[derive(Default)]
+struct WriteBatch {
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    fn put(&mut self, column: Column, key: &[u8], value: &[u8]) {
+        self.ops.push((column.key(key), Some(value.to_vec())));
+    }
+
+    fn delete(&mut self, column: Column, key: &[u8]) {
+        self.ops.push((column.key(key), None));
+    }
+}
+
+/// Node storage shared between trees, kept in LevelDB or in memory. Both do
+/// their own locking, so the database is `Send + Sync` and all operations
+/// take `&self`. Nodes, reference counts and everything else live in their
+/// own `Column`.
+///
+/// Every stored node has a reference count: one for each stored node that
+/// points to it plus one for each commit that produced it as a root. Pruning
+/// a root releases the commit's reference and deletes whatever is no longer
+/// referenced, so a node can keep only its most recent states.
 pub struct Database {
-    db_impl: database::Database,
+    db_impl: Backend,
+    // serializes reference count updates of concurrent commits and prunes
+    ref_count_lock: Mutex<()>,
+    // serializes block root updates, which read the old mapping first
+    block_root_lock: Mutex<()>,
+    verify_hashes: AtomicBool,
+    decode_strict: AtomicBool,
+    record_preimages: AtomicBool,
 }
 
 impl Database {
-    pub fn new(path: &str) -> Box<Self> {
+    /// Opens the LevelDB database at `path`, creating it if it doesn't
+    /// exist. Fails for databases with another key layout, see
+    /// `SCHEMA_VERSION`.
+    pub fn new(path: &str) -> Result<Arc<Self>, SchemaError> {
         use std::path::Path;
         let mut options = Options::new();
         options.create_if_missing = true;
-        Box::new(Database {
-            db_impl: database::Database::open(Path::new(path), options).unwrap(),
-        })
+        Self::with_backend(Backend::LevelDb(database::Database::open(Path::new(path), options).unwrap()))
+    }
+
+    /// Database that keeps everything in memory and is gone when dropped,
+    /// e.g. for tests, benchmarks and short lived trees
+    pub fn in_memory() -> Arc<Self> {
+        // an empty database gets the current schema
+        Self::with_backend(Backend::Memory(RwLock::new(BTreeMap::new()))).unwrap()
+    }
+
+    fn with_backend(db_impl: Backend) -> Result<Arc<Self>, SchemaError> {
+        let db = Database {
+            db_impl,
+            ref_count_lock: Mutex::new(()),
+            block_root_lock: Mutex::new(()),
+            verify_hashes: AtomicBool::new(cfg!(debug_assertions)),
+            decode_strict: AtomicBool::new(false),
+            record_preimages: AtomicBool::new(false),
+        };
+        match db.schema_version() {
+            Some(SCHEMA_VERSION) => {},
+            Some(version) => return Err(SchemaError::UnsupportedVersion(version)),
+            None if db.is_empty() => db.write_one(Column::Meta, SCHEMA_VERSION_KEY, Some(&rlp::encode(&SCHEMA_VERSION)[..])),
+            None => return Err(SchemaError::LegacyLayout),
+        }
+        Ok(Arc::new(db))
+    }
+
+    /// Whether trees check that nodes they load hash to the hash they were
+    /// loaded by. On by default in debug builds.
+    pub fn verify_hashes(&self) -> bool {
+        self.verify_hashes.load(Ordering::Relaxed)
+    }
+
+    pub fn set_verify_hashes(&self, verify: bool) {
+        self.verify_hashes.store(verify, Ordering::Relaxed);
+    }
+
+    /// Whether trees decode the nodes they load with the strict decoder and
+    /// reject non-canonical ones, for nodes that aren't trusted, e.g. those
+    /// of a witness. Off by default.
+    pub fn decode_strict(&self) -> bool {
+        self.decode_strict.load(Ordering::Relaxed)
+    }
+
+    pub fn set_decode_strict(&self, strict: bool) {
+        self.decode_strict.store(strict, Ordering::Relaxed);
+    }
+
+    /// Whether secure trees store the keys they hash, so iteration can show
+    /// them. Off by default, it costs a write per new key.
+    pub fn record_preimages(&self) -> bool {
+        self.record_preimages.load(Ordering::Relaxed)
+    }
+
+    pub fn set_record_preimages(&self, record: bool) {
+        self.record_preimages.store(record, Ordering::Relaxed);
+    }
+
+    /// Stores the keys by their keccak hash in one batch
+    pub fn insert_preimages(&self, preimages: &Vec<(H256, Vec<u8>)>) {
+        let mut batch = WriteBatch::default();
+
+        for (hash, preimage) in preimages {
+            batch.put(Column::Preimages, hash, preimage);
+        }
+        self.write(batch);
+    }
+
+    /// Key that hashes to `hash`, if it was recorded
+    pub fn preimage(&self, hash: &H256) -> Option<Vec<u8>> {
+        self.get(Column::Preimages, hash)
+    }
+
+    pub fn get(&self, column: Column, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_raw(&column.key(key))
+    }
+
+    /// Stores `value` under `key` in `column`. Panics for `Column::Nodes`
+    /// and `Column::RefCounts`, nodes are written by `insert_nodes` and the
+    /// other node methods, which keep the reference counts right.
+    pub fn put(&self, column: Column, key: &[u8], value: &[u8]) {
+        Self::check_writable(column);
+        self.write_one(column, key, Some(value));
+    }
+
+    /// Deletes `key` from `column`, panics like `put`
+    pub fn delete(&self, column: Column, key: &[u8]) {
+        Self::check_writable(column);
+        self.write_one(column, key, None);
+    }
+
+    /// Calls `f` for every key and value of the column in key order
+    pub fn for_each<F>(&self, column: Column, mut f: F) where F: FnMut(&[u8], &[u8]) {
+        self.for_each_raw(&[column.prefix()], |key, value| f(&key[1..], value));
+    }
+
+    /// Schema version the database was created with
+    pub fn schema_version(&self) -> Option<u32> {
+        self.get(Column::Meta, SCHEMA_VERSION_KEY).map(|data| rlp::decode(&data[..]))
+    }
+
+    /// Stores `root` under `name`, e.g. `LATEST_ROOT`. Only the name is
+    /// stored, the root keeps its nodes by its commit's reference.
+    pub fn set_root(&self, name: &str, root: &H256) {
+        self.put(Column::Meta, &Self::meta_key(NAMED_ROOT_PREFIX, name.as_bytes()), root);
+    }
+
+    pub fn root(&self, name: &str) -> Option<H256> {
+        self.get(Column::Meta, &Self::meta_key(NAMED_ROOT_PREFIX, name.as_bytes()))
+            .map(|data| H256::from_slice(&data[..]))
+    }
+
+    pub fn delete_root(&self, name: &str) {
+        self.delete(Column::Meta, &Self::meta_key(NAMED_ROOT_PREFIX, name.as_bytes()));
+    }
+
+    /// Maps block `number` to `root` and back. A block that is set again,
+    /// e.g. after a reorg, no longer maps from its old root. Blocks with the
+    /// same root map back from it to the one set last.
+    pub fn set_block_root(&self, number: u64, root: &H256) {
+        let _lock = self.block_root_lock.lock().unwrap();
+        let number_key = Self::block_number_key(number);
+        let mut batch = WriteBatch::default();
+
+        if let Some(old_root) = self.block_root(number) {
+            // the old root may map to another block by now
+            if self.block_number(&old_root) == Some(number) {
+                batch.delete(Column::Meta, &Self::meta_key(ROOT_BLOCK_PREFIX, &old_root));
+            }
+        }
+        batch.put(Column::Meta, &Self::meta_key(BLOCK_ROOT_PREFIX, &number_key), root);
+        batch.put(Column::Meta, &Self::meta_key(ROOT_BLOCK_PREFIX, root), &number_key);
+        self.write(batch);
+    }
+
+    /// Root of block `number`
+    pub fn block_root(&self, number: u64) -> Option<H256> {
+        self.get(Column::Meta, &Self::meta_key(BLOCK_ROOT_PREFIX, &Self::block_number_key(number)))
+            .map(|data| H256::from_slice(&data[..]))
+    }
+
+    /// Number of the block with state `root`
+    pub fn block_number(&self, root: &H256) -> Option<u64> {
+        self.get(Column::Meta, &Self::meta_key(ROOT_BLOCK_PREFIX, root))
+            .map(|data| data.iter().fold(0, |number, byte| number << 8 | *byte as u64))
     }
 
     pub fn get_value(&self, key: &H256) -> Option<Vec<u8>> {
-        match self.db_impl.get(ReadOptions::new(), key) {
-            Ok(data) => data,
-            Err(e) => panic!("failed reading data: {:?}", e),
+        self.get(Column::Nodes, key)
+    }
+
+    // Writes a node without touching reference counts, for tests that
+    // corrupt or lose stored nodes
+    #[cfg(test)]
+    pub(crate) fn set_value(&self, key: &H256, value: &[u8]) {
+        self.write_one(Column::Nodes, key, Some(value));
+    }
+
+    #[cfg(test)]
+    pub(crate) fn delete_value(&self, key: &H256) {
+        self.write_one(Column::Nodes, key, None);
+    }
+
+    pub fn ref_count(&self, hash: &H256) -> u32 {
+        match self.get(Column::RefCounts, hash) {
+            Some(data) => rlp::decode(&data[..]),
+            None => 0,
         }
     }
 
-    pub fn set_value(&mut self, key: &H256, value: &Vec<u8>) {
-        match self.db_impl.put(WriteOptions::new(), key, value) {
-            Ok(_) => {}
-            Err(e) => panic!("failed to write to database: {:?}", e),
-        };
+    /// Stores the nodes of one commit, children before their parents, and
+    /// adds a reference to `root`. A node that is already stored is not
+    /// written again: its children were counted when it was first stored.
+    pub fn insert_nodes(&self, nodes: &Vec<(H256, Vec<u8>)>, root: &H256) -> UndoLog {
+        self.write_nodes(WriteBatch::default(), nodes, &[*root]);
+        UndoLog {
+            inserted: Some(*root),
+            pruned: None,
+        }
+    }
+
+    /// Stores nodes like `insert_nodes` without adding a root reference, for
+    /// nodes that are written before their tree is complete, e.g. by trie sync
+    pub fn store_nodes(&self, nodes: &Vec<(H256, Vec<u8>)>) {
+        self.write_nodes(WriteBatch::default(), nodes, &[])
     }
 
-    pub fn delete_value(&mut self, key: &H256) {
-        match self.db_impl.delete(WriteOptions::new(), key) {
-            Ok(_) => {}
-            Err(e) => panic!("failed to delete from database: {:?}", e),
+    /// Writes a state commit in one batch: the nodes of the storage tries and
+    /// the account trie, children before their parents, a reference to each
+    /// of `roots` like `insert_nodes` adds, the new contract code and the
+    /// key preimages.
+    pub(crate) fn insert_state(&self, nodes: &Vec<(H256, Vec<u8>)>, roots: &[H256], code: &[Vec<u8>],
+        preimages: &[(H256, Vec<u8>)])
+    {
+        let mut batch = WriteBatch::default();
+
+        for code in code {
+            batch.put(Column::Code, &keccak(code), code);
+        }
+        for (hash, preimage) in preimages {
+            batch.put(Column::Preimages, hash, preimage);
         }
+        self.write_nodes(batch, nodes, roots)
+    }
+
+    // Adds the nodes and the root references to `batch` and writes it
+    fn write_nodes(&self, mut batch: WriteBatch, nodes: &Vec<(H256, Vec<u8>)>, roots: &[H256]) {
+        let _lock = self.ref_count_lock.lock().unwrap();
+        let mut counts = HashMap::new();
+        let mut written = HashSet::new();
+
+        for (hash, data) in nodes {
+            if written.contains(hash) || self.get_value(hash).is_some() {
+                continue;
+            }
+            written.insert(*hash);
+            batch.put(Column::Nodes, hash, data);
+
+            for child in child_hashes(&data[..]) {
+                *self.pending_count(&mut counts, &child) += 1;
+            }
+        }
+        for root in roots {
+            *self.pending_count(&mut counts, root) += 1;
+        }
+        self.write_counts(batch, counts);
+    }
+
+    /// Releases the reference a commit holds on `root` and deletes every node
+    /// that is no longer referenced. Nodes stored before reference counting
+    /// have no count and are never deleted here. Returns the number of
+    /// deleted nodes.
+    pub fn prune_root(&self, root: &H256) -> usize {
+        self.prune_root_with_undo(root).0
+    }
+
+    /// Same as `prune_root`, also returns what is needed to bring the deleted
+    /// nodes back
+    pub fn prune_root_with_undo(&self, root: &H256) -> (usize, UndoLog) {
+        let _lock = self.ref_count_lock.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        let mut counts = HashMap::new();
+        let mut stack = vec![*root];
+        let mut deleted = Vec::new();
+
+        // a root without a reference isn't released, there is nothing to undo
+        if self.ref_count(root) == 0 {
+            return (0, UndoLog::default())
+        }
+        while let Some(hash) = stack.pop() {
+            {
+                let count = self.pending_count(&mut counts, &hash);
+                if *count == 0 {
+                    continue;
+                }
+                *count -= 1;
+                if *count > 0 {
+                    continue;
+                }
+            }
+            if let Some(data) = self.get_value(&hash) {
+                batch.delete(Column::Nodes, &hash);
+                // a deleted node no longer references its children
+                stack.extend(child_hashes(&data[..]));
+                deleted.push((hash, data));
+            }
+        }
+        self.write_counts(batch, counts);
+        let count = deleted.len();
+        (count, UndoLog {
+            inserted: None,
+            pruned: Some((*root, deleted)),
+        })
+    }
+
+    /// Takes back what a commit or a prune did. A commit's root reference is
+    /// released like `prune_root` does, nodes that later writes reference
+    /// too are kept. A prune's reference is added again and the nodes it
+    /// deleted are stored again, counting their children like `insert_nodes`.
+    pub fn undo(&self, undo: &UndoLog) {
+        if let Some(ref root) = undo.inserted {
+            self.prune_root(root);
+        }
+        if let Some((ref root, ref deleted)) = undo.pruned {
+            // children before their parents
+            let nodes: Vec<_> = deleted.iter().rev().cloned().collect();
+            self.write_nodes(WriteBatch::default(), &nodes, &[*root]);
+        }
+    }
+
+    /// Calls `f` for every stored node in key order
+    pub fn for_each_node<F>(&self, mut f: F) where F: FnMut(&H256, &[u8]) {
+        self.for_each(Column::Nodes, |key, value| f(&H256::from_slice(key), value));
+    }
+
+    /// Deletes the nodes together with their reference counts in one batch.
+    /// The nodes no longer reference their children, so children that are
+    /// kept lose one reference for each deleted parent.
+    pub fn delete_nodes(&self, hashes: &Vec<H256>) {
+        let _lock = self.ref_count_lock.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        let mut counts = HashMap::new();
+        let deleted: HashSet<_> = hashes.iter().cloned().collect();
+
+        for hash in hashes {
+            if let Some(data) = self.get_value(hash) {
+                for child in child_hashes(&data[..]) {
+                    if deleted.contains(&child) {
+                        continue;
+                    }
+                    let count = self.pending_count(&mut counts, &child);
+                    // nodes stored before reference counting have no count
+                    if *count > 0 {
+                        *count -= 1;
+                    }
+                }
+            }
+            batch.delete(Column::Nodes, hash);
+            batch.delete(Column::RefCounts, hash);
+        }
+        self.write_counts(batch, counts);
+    }
+
+    /// Stores contract code under its keccak hash and returns the hash. Code
+    /// isn't reference counted: `prune_root` keeps it, `gc::collect_state_garbage`
+    /// deletes code that no account refers to.
+    pub fn insert_code(&self, code: &[u8]) -> H256 {
+        let hash = keccak(code);
+        self.put(Column::Code, &hash, code);
+        hash
+    }
+
+    pub fn get_code(&self, hash: &H256) -> Option<Vec<u8>> {
+        self.get(Column::Code, hash)
+    }
+
+    /// Calls `f` for all stored code in key order
+    pub fn for_each_code<F>(&self, mut f: F) where F: FnMut(&H256, &[u8]) {
+        self.for_each(Column::Code, |key, value| f(&H256::from_slice(key), value));
+    }
+
+    /// Deletes the code with these hashes in one batch
+    pub fn delete_code(&self, hashes: &Vec<H256>) {
+        let mut batch = WriteBatch::default();
+
+        for hash in hashes {
+            batch.delete(Column::Code, hash);
+        }
+        self.write(batch);
+    }
+
+    fn check_writable(column: Column) {
+        match column {
+            Column::Nodes | Column::RefCounts => panic!("{:?} can't be written directly", column),
+            _ => {},
+        }
+    }
+
+    fn write_one(&self, column: Column, key: &[u8], value: Option<&[u8]>) {
+        let mut batch = WriteBatch::default();
+        match value {
+            Some(value) => batch.put(column, key, value),
+            None => batch.delete(column, key),
+        }
+        self.write(batch);
+    }
+
+    // Whether no key is stored at all, in any column or outside of them
+    fn is_empty(&self) -> bool {
+        match self.db_impl {
+            Backend::LevelDb(ref db) => db.iter(ReadOptions::new()).next().is_none(),
+            Backend::Memory(ref map) => map.read().unwrap().is_empty(),
+        }
+    }
+
+    fn get_raw(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.db_impl {
+            Backend::LevelDb(ref db) => match db.get(ReadOptions::new(), key) {
+                Ok(data) => data,
+                Err(e) => panic!("failed reading data: {:?}", e),
+            },
+            Backend::Memory(ref map) => map.read().unwrap().get(key).cloned(),
+        }
+    }
+
+    // Writes the whole batch atomically
+    fn write(&self, batch: WriteBatch) {
+        match self.db_impl {
+            Backend::LevelDb(ref db) => {
+                let mut level_batch = Writebatch::new();
+                for (key, value) in batch.ops {
+                    match value {
+                        Some(value) => level_batch.put(key, &value),
+                        None => level_batch.delete(key),
+                    }
+                }
+                match db.write(WriteOptions::new(), &level_batch) {
+                    Ok(_) => {}
+                    Err(e) => panic!("failed to write to database: {:?}", e),
+                };
+            },
+            Backend::Memory(ref map) => {
+                let mut map = map.write().unwrap();
+                for (key, value) in batch.ops {
+                    match value {
+                        Some(value) => { map.insert(key, value); },
+                        None => { map.remove(&key); },
+                    }
+                }
+            }
+        }
+    }
+
+    // Keys starting with `prefix` in key order
+    fn for_each_raw<F>(&self, prefix: &[u8], mut f: F) where F: FnMut(&[u8], &[u8]) {
+        match self.db_impl {
+            Backend::LevelDb(ref db) => {
+                let mut iter = db.iter(ReadOptions::new());
+
+                iter.seek(prefix);
+                while let Some((key, value)) = iter.next() {
+                    if !key.starts_with(prefix) {
+                        break;
+                    }
+                    f(key, value);
+                }
+            },
+            Backend::Memory(ref map) => {
+                // a copy, so `f` can use the database
+                let entries: Vec<_> = map.read().unwrap().range(prefix.to_vec()..)
+                    .take_while(|&(key, _)| key.starts_with(prefix))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                for (key, value) in entries {
+                    f(&key[..], &value[..]);
+                }
+            }
+        }
+    }
+
+    fn meta_key(prefix: &[u8], key: &[u8]) -> Vec<u8> {
+        let mut result = prefix.to_vec();
+        result.extend_from_slice(key);
+        result
+    }
+
+    // big endian, so blocks iterate in order
+    fn block_number_key(number: u64) -> [u8; 8] {
+        let mut key = [0u8; 8];
+        for (index, byte) in key.iter_mut().enumerate() {
+            *byte = (number >> (56 - index * 8)) as u8;
+        }
+        key
+    }
+
+    fn pending_count<'a>(&self, counts: &'a mut HashMap<H256, u32>, hash: &H256) -> &'a mut u32 {
+        if !counts.contains_key(hash) {
+            counts.insert(*hash, self.ref_count(hash));
+        }
+        counts.get_mut(hash).unwrap()
+    }
+
+    // Adds the updated counts to the batch and writes everything at once.
+    // Nothing is written before this, so the stored counts are the old ones.
+    fn write_counts(&self, mut batch: WriteBatch, counts: HashMap<H256, u32>) {
+        for (hash, count) in counts {
+            if count == 0 {
+                batch.delete(Column::RefCounts, &hash);
+            } else {
+                batch.put(Column::RefCounts, &hash, &rlp::encode(&count)[..]);
+            }
+        }
+        self.write(batch);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tree::MerkleTree;
+    use rlp::RlpStream;
+    use std::collections::VecDeque;
     use std::panic;
 
-    fn run_test<T>(test: T) -> ()
-    where
-        T: FnOnce() -> () + panic::UnwindSafe,
-    {
-        use std::path::Path;
-        use std::fs;
+    fn reachable_nodes(db: &Database, root: &H256) -> HashSet<H256> {
+        let mut result = HashSet::new();
+        let mut stack = vec![*root];
 
-        fs::remove_dir_all(Path::new("storage_test"));
+        while let Some(hash) = stack.pop() {
+            if let Some(data) = db.get_value(&hash) {
+                stack.extend(child_hashes(&data[..]));
+                result.insert(hash);
+            }
+        }
+        result
+    }
 
-        let result = panic::catch_unwind(|| test());
+    #[test]
+    fn basic_database_test() {
+        let db = Database::in_memory();
+        let mut rlp_s = RlpStream::new_list(2);
+        rlp_s.append(&vec![0x20u8]).append(&vec![0x01u8, 0x02, 0x03, 0x04, 0x05]);
+        let data = rlp_s.out();
+        let hash = keccak(&data[..]);
 
-        fs::remove_dir_all(Path::new("storage_test"));
+        db.insert_nodes(&vec![(hash, data.clone())], &hash);
+        assert_eq!(db.get_value(&hash), Some(data));
+        assert_eq!(db.ref_count(&hash), 1);
 
-        assert!(result.is_ok())
+        assert_eq!(db.prune_root(&hash), 1);
+        assert!(db.get_value(&hash).is_none());
+        assert_eq!(db.ref_count(&hash), 0);
     }
 
     #[test]
-    fn basic_database_test() {
-        run_test(|| {
-            let mut db = Database::new("storage_test");
-            db.set_value(&H256::from(1 as u64), &vec![0x01, 0x02, 0x03, 0x04, 0x05]);
+    fn in_memory_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
 
-            if let Some(value) = db.get_value(&H256::from(1 as u64)) {
-                assert_eq!(value, vec![0x01, 0x02, 0x03, 0x04, 0x05]);
-            } else {
-                assert!(false);
-            }
-            db.delete_value(&H256::from(1 as u64));
+        for index in 0..50 {
+            tree.update(&H256::from(index * 0x1000001u64), Some(index)).unwrap();
+        }
+        let root = tree.commit();
+        let mut nodes = 0;
+        db.for_each_node(|hash, data| {
+            assert_eq!(keccak(data), *hash);
+            nodes += 1;
+        });
+        assert_eq!(reachable_nodes(&db, &root).len(), nodes);
 
-            if let Some(value) = db.get_value(&H256::from(1 as u64)) {
-                assert!(false);
-            }
-        })
+        let tree = MerkleTree::<u64>::at_root(db.clone(), root).unwrap();
+        for index in 0..50 {
+            assert_eq!(tree.get(&H256::from(index * 0x1000001u64)).unwrap(), Some(index));
+        }
+        assert_eq!(db.prune_root(&root), nodes);
+        assert_eq!(db.ref_count(&root), 0);
+        db.for_each_node(|_, _| panic!("pruned node left behind"));
+    }
+
+    #[test]
+    fn code_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        tree.update(&H256::from(1u64), Some(1)).unwrap();
+        let root = tree.commit();
+        let hash = db.insert_code(b"contract code");
+        assert_eq!(hash, keccak(b"contract code"));
+        assert_eq!(db.get_code(&hash), Some(b"contract code".to_vec()));
+        // code and nodes don't share keys
+        assert_eq!(db.get_value(&hash), None);
+        db.for_each_node(|node, _| assert!(*node != hash));
+        let mut codes = Vec::new();
+        db.for_each_code(|hash, code| codes.push((*hash, code.to_vec())));
+        assert_eq!(codes, vec![(hash, b"contract code".to_vec())]);
+
+        db.prune_root(&root);
+        assert!(db.get_code(&hash).is_some());
+        db.delete_code(&vec![hash]);
+        assert_eq!(db.get_code(&hash), None);
+    }
+
+    #[test]
+    fn column_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        tree.update(&H256::from(1u64), Some(1)).unwrap();
+        let root = tree.commit();
+        db.put(Column::Meta, b"latest", &root);
+        db.put(Column::Meta, b"name", b"value");
+        db.put(Column::Preimages, b"name", b"other value");
+        // the same key in another column
+        db.put(Column::Preimages, &root, b"not a node");
+
+        assert_eq!(db.get(Column::Meta, b"latest"), Some(root.to_vec()));
+        assert_eq!(db.get(Column::Preimages, b"name"), Some(b"other value".to_vec()));
+        assert!(db.get(Column::Nodes, &root).unwrap() != b"not a node".to_vec());
+        assert_eq!(db.get(Column::Code, b"name"), None);
+        // nodes and their counts only change through the node methods
+        for &column in [Column::Nodes, Column::RefCounts].iter() {
+            let db = panic::AssertUnwindSafe(db.clone());
+            assert!(panic::catch_unwind(|| db.put(column, b"name", b"value")).is_err());
+            assert!(panic::catch_unwind(|| db.delete(column, &root)).is_err());
+        }
+        assert!(db.get(Column::Nodes, &root).is_some());
+
+        let mut preimages = Vec::new();
+        db.for_each(Column::Preimages, |key, value| preimages.push((key.to_vec(), value.to_vec())));
+        assert_eq!(preimages, vec![(root.to_vec(), b"not a node".to_vec()), (b"name".to_vec(), b"other value".to_vec())]);
+        let mut nodes = 0;
+        db.for_each_node(|hash, data| {
+            assert_eq!(keccak(data), *hash);
+            nodes += 1;
+        });
+        assert_eq!(nodes, 1);
+
+        db.delete(Column::Meta, b"name");
+        assert_eq!(db.get(Column::Meta, b"name"), None);
+        assert_eq!(db.get(Column::Preimages, b"name"), Some(b"other value".to_vec()));
+        assert_eq!(db.prune_root(&root), 1);
+        assert_eq!(db.get(Column::Preimages, &root), Some(b"not a node".to_vec()));
+    }
+
+    #[test]
+    fn metadata_test() {
+        let db = Database::in_memory();
+        assert_eq!(db.schema_version(), Some(SCHEMA_VERSION));
+        assert_eq!(db.root(LATEST_ROOT), None);
+
+        db.set_root(LATEST_ROOT, &H256::from(2u64));
+        db.set_root(FINALIZED_ROOT, &H256::from(1u64));
+        db.set_block_root(1, &H256::from(1u64));
+        db.set_block_root(0x100, &H256::from(2u64));
+        // block 0x100 reorged to another root
+        db.set_block_root(0x100, &H256::from(3u64));
+        assert_eq!(db.root(LATEST_ROOT), Some(H256::from(2u64)));
+        assert_eq!(db.root(FINALIZED_ROOT), Some(H256::from(1u64)));
+        assert_eq!(db.block_root(1), Some(H256::from(1u64)));
+        assert_eq!(db.block_root(0x100), Some(H256::from(3u64)));
+        assert_eq!(db.block_root(2), None);
+        assert_eq!(db.block_number(&H256::from(3u64)), Some(0x100));
+        assert_eq!(db.block_number(&H256::from(2u64)), None);
+        assert_eq!(db.block_number(&H256::from(1u64)), Some(1));
+
+        // blocks 5 and 6 with the same root, then block 5 is reorged
+        db.set_block_root(5, &H256::from(5u64));
+        db.set_block_root(6, &H256::from(5u64));
+        db.set_block_root(5, &H256::from(6u64));
+        assert_eq!(db.block_number(&H256::from(5u64)), Some(6));
+        assert_eq!(db.block_number(&H256::from(6u64)), Some(5));
+        db.set_block_root(6, &H256::from(7u64));
+        assert_eq!(db.block_number(&H256::from(5u64)), None);
+
+        db.delete_root(FINALIZED_ROOT);
+        assert_eq!(db.root(FINALIZED_ROOT), None);
     }
 
+    #[test]
+    fn schema_version_test() {
+        let mut map = BTreeMap::new();
+        map.insert(Column::Meta.key(SCHEMA_VERSION_KEY), rlp::encode(&(SCHEMA_VERSION + 1)).to_vec());
+        let newer = Backend::Memory(RwLock::new(map));
+        assert_eq!(Database::with_backend(newer).err(), Some(SchemaError::UnsupportedVersion(SCHEMA_VERSION + 1)));
+    }
+
+    #[test]
+    fn legacy_layout_test() {
+        // nodes under bare hashes, as written before columns
+        let mut map = BTreeMap::new();
+        map.insert(keccak(&[0xc1, 0x01]).to_vec(), vec![0xc1, 0x01]);
+        let legacy = Backend::Memory(RwLock::new(map));
+        assert_eq!(Database::with_backend(legacy).err(), Some(SchemaError::LegacyLayout));
 
-}
\ No newline at end of file
+        let mut map = BTreeMap::new();
+        map.insert(b"refcount:".to_vec(), vec![1]);
+        let legacy = Backend::Memory(RwLock::new(map));
+        assert_eq!(Database::with_backend(legacy).err(), Some(SchemaError::LegacyLayout));
+    }
+
+    #[test]
+    fn prune_root_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..50 {
+            tree.update(&H256::from(index * 0x1000001u64), Some(index)).unwrap();
+        }
+        let first = tree.commit();
+        tree.update(&H256::from(0u64), Some(100)).unwrap();
+        tree.update(&H256::from(7u64), Some(7)).unwrap();
+        let second = tree.commit();
+
+        assert_eq!(db.ref_count(&first), 1);
+        assert_eq!(db.ref_count(&second), 1);
+        let first_nodes = reachable_nodes(&db, &first);
+        let second_nodes = reachable_nodes(&db, &second);
+        let shared = first_nodes.intersection(&second_nodes).count();
+        assert!(shared > 0);
+
+        assert_eq!(db.prune_root(&first), first_nodes.len() - shared);
+        assert!(MerkleTree::<u64>::at_root(db.clone(), first).is_err());
+        let tree = MerkleTree::<u64>::at_root(db.clone(), second).unwrap();
+        for index in 1..50 {
+            assert_eq!(tree.get(&H256::from(index * 0x1000001u64)).unwrap(), Some(index));
+        }
+        assert_eq!(tree.get(&H256::from(0u64)).unwrap(), Some(100));
+
+        assert_eq!(db.prune_root(&second), second_nodes.len());
+        for hash in first_nodes.union(&second_nodes) {
+            assert_eq!(db.get_value(hash), None);
+            assert_eq!(db.ref_count(hash), 0);
+        }
+        // nothing left to release
+        assert_eq!(db.prune_root(&second), 0);
+    }
+
+    #[test]
+    fn repeated_root_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..10 {
+            tree.update(&H256::from(index), Some(index)).unwrap();
+        }
+        let root = tree.commit();
+        // a commit without changes still holds its own reference
+        assert_eq!(tree.commit(), root);
+        assert_eq!(db.ref_count(&root), 2);
+
+        assert_eq!(db.prune_root(&root), 0);
+        assert!(MerkleTree::<u64>::at_root(db.clone(), root).is_ok());
+        assert!(db.prune_root(&root) > 0);
+        assert!(MerkleTree::<u64>::at_root(db.clone(), root).is_err());
+    }
+
+    #[test]
+    fn keep_recent_roots_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let mut retained = VecDeque::new();
+        let mut pruned = Vec::new();
+
+        for round in 0..200 {
+            tree.update(&H256::from(round % 17u64), Some(round)).unwrap();
+            tree.update(&H256::from(round * 31u64), Some(round)).unwrap();
+            retained.push_back((round, tree.commit()));
+
+            if retained.len() > 128 {
+                pruned.push(retained.pop_front().unwrap().1);
+                db.prune_root(&pruned[pruned.len() - 1]);
+            }
+        }
+        for &(round, root) in retained.iter() {
+            let tree = MerkleTree::<u64>::at_root(db.clone(), root).unwrap();
+            assert_eq!(tree.get(&H256::from(round * 31u64)).unwrap(), Some(round));
+            assert_eq!(tree.get(&H256::from(round % 17u64)).unwrap(), Some(round));
+        }
+        for root in pruned {
+            assert!(MerkleTree::<u64>::at_root(db.clone(), root).is_err());
+        }
+    }
+}
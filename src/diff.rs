@@ -0,0 +1,255 @@
+use ethereum_types::H256;
+use rlp;
+use rlp::{Encodable, Decodable};
+use node::*;
+use db::*;
+use error::TrieError;
+use nibble::NibbleVec;
+use tree::NodeSource;
+use std::sync::Arc;
+
+/// Change of a single key between two roots
+#[derive(Debug, PartialEq)]
+pub enum Change<T> {
+    Added(H256, T),
+    Removed(H256, T),
+    /// Key with the old and the new value
+    Changed(H256, T, T),
+}
+
+impl<T> Change<T> {
+    pub fn key(&self) -> &H256 {
+        match *self {
+            Change::Added(ref key, _) |
+            Change::Removed(ref key, _) |
+            Change::Changed(ref key, _, _) => key,
+        }
+    }
+}
+
+// Node, the number of nibbles of its short key that are already part of
+// the path, so a short node can be stepped through one nibble at a time, and
+// the hash of the stored node it is in
+type Cursor<T> = (Arc<Node<T>>, usize, H256);
+
+// Children of a cursor by the next nibble, and the value at its path with
+// the hash of the stored node it is in
+type Expanded<T> = (Vec<Option<Cursor<T>>>, Option<(T, H256)>);
+
+/// Changes that turn the tree at `old_root` into the tree at `new_root`,
+/// ordered by key. Subtrees with the same hash in both trees are skipped
+/// without loading them, so the cost depends on the size of the change.
+pub fn diff<T: Encodable + Decodable + Clone>(db: &Database, old_root: &H256, new_root: &H256) -> Result<Vec<Change<T>>, TrieError> {
+    let mut changes = Vec::new();
+    let old = root_cursor(old_root);
+    let new = root_cursor(new_root);
+
+    diff_helper(db, &mut Vec::new(), old, new, &mut changes)?;
+    Ok(changes)
+}
+
+fn root_cursor<T: Decodable>(root: &H256) -> Option<Cursor<T>> {
+    if *root == EMPTY_ROOT {
+        return None
+    }
+    Some((Arc::new(Node::HashNode {hash: *root}), 0, *root))
+}
+
+fn diff_helper<T: Encodable + Decodable + Clone>(db: &Database, path: &mut Vec<u8>, old: Option<Cursor<T>>,
+    new: Option<Cursor<T>>, changes: &mut Vec<Change<T>>) -> Result<(), TrieError>
+{
+    if let (Some(old), Some(new)) = (&old, &new) {
+        if let (Some(old_hash), Some(new_hash)) = (stored_hash(&old.0), stored_hash(&new.0)) {
+            if old_hash == new_hash && old.1 == new.1 {
+                return Ok(())
+            }
+        }
+    }
+    let (old_children, old_value) = expand(db, path, old)?;
+    let (new_children, new_value) = expand(db, path, new)?;
+
+    match (old_value, new_value) {
+        (Some((old, _)), Some((new, hash))) => {
+            if rlp::encode(&old)[..] != rlp::encode(&new)[..] {
+                changes.push(Change::Changed(path_to_key(&hash, path)?, old, new));
+            }
+        },
+        (Some((old, hash)), None) => changes.push(Change::Removed(path_to_key(&hash, path)?, old)),
+        (None, Some((new, hash))) => changes.push(Change::Added(path_to_key(&hash, path)?, new)),
+        (None, None) => {}
+    }
+    for (index, (old, new)) in old_children.into_iter().zip(new_children).enumerate() {
+        if old.is_none() && new.is_none() {
+            continue;
+        }
+        path.push(index as u8);
+        diff_helper(db, path, old, new, changes)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+// Hash of the stored node behind the cursor, inlined and changed nodes have none
+fn stored_hash<T: Decodable>(node: &Node<T>) -> Option<H256> {
+    match *node {
+        Node::HashNode {ref hash} => Some(*hash),
+        Node::FullNode {ref flags, ..} | Node::ShortNode {ref flags, ..} => {
+            if flags.dirty || flags.hash.is_zero() {
+                return None
+            }
+            Some(flags.hash)
+        },
+        _ => None,
+    }
+}
+
+// Children of the cursor at `path` by the next nibble, and the value at the
+// path itself with the hash of the stored node it is in
+fn expand<T: Decodable + Clone>(db: &Database, path: &[u8], cursor: Option<Cursor<T>>) -> Result<Expanded<T>, TrieError> {
+    let mut children = vec![None; 16];
+    let (node, skip, stored) = match cursor {
+        Some(cursor) => cursor,
+        None => return Ok((children, None)),
+    };
+    match *node {
+        Node::FullNode {ref nibles, ..} => {
+            for index in 0..16 {
+                children[index] = nibles[index].as_ref().map(|child| (child.clone(), 0, stored));
+            }
+            let value = match nibles[16] {
+                Some(ref child) => expand(db, path, Some((child.clone(), 0, stored)))?.1,
+                None => None,
+            };
+            Ok((children, value))
+        },
+        Node::ShortNode {ref key, node: ref child, ..} => {
+            if skip == key.len() {
+                return expand(db, path, Some((child.clone(), 0, stored)))
+            }
+            children[key.at(skip) as usize] = Some((node.clone(), skip + 1, stored));
+            Ok((children, None))
+        },
+        Node::HashNode {ref hash} => {
+            let mut nibbles = NibbleVec::new();
+            for &nibble in path {
+                nibbles.push(nibble);
+            }
+            let node = NodeSource::new(db, &None).load_node(hash, nibbles.as_slice())?;
+            expand(db, path, Some((node, 0, *hash)))
+        },
+        Node::ValueNode {ref value} => {
+            Ok((children, Some((value.clone(), stored))))
+        },
+        Node::Empty => {
+            Ok((children, None))
+        }
+    }
+}
+
+// Key of a value at `path`, values of the tree at `hash` can only be at
+// full keys
+fn path_to_key(hash: &H256, path: &[u8]) -> Result<H256, TrieError> {
+    if path.len() != 64 {
+        return Err(TrieError::InvalidNode(*hash, path.to_vec()))
+    }
+    let mut key = H256::zero();
+
+    for (index, pair) in path.chunks(2).enumerate() {
+        key[index] = (pair[0] << 4) | pair[1];
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::MerkleTree;
+    use std::collections::HashSet;
+    use rlp::RlpStream;
+
+    #[test]
+    fn diff_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..200 {
+            tree.update(&H256::from(index * 0x1001u64), Some(index)).unwrap();
+        }
+        let old_root = tree.commit();
+        tree.update(&H256::from(3 * 0x1001u64), Some(1000)).unwrap();
+        tree.update(&H256::from(7 * 0x1001u64), None).unwrap();
+        tree.update(&H256::from(5u64), Some(5)).unwrap();
+        // same value again is no change
+        tree.update(&H256::from(9 * 0x1001u64), Some(9)).unwrap();
+        let new_root = tree.commit();
+
+        let changes = diff::<u64>(&db, &old_root, &new_root).unwrap();
+        assert_eq!(changes, vec![
+            Change::Added(H256::from(5u64), 5),
+            Change::Changed(H256::from(3 * 0x1001u64), 3, 1000),
+            Change::Removed(H256::from(7 * 0x1001u64), 7),
+        ]);
+        let changes = diff::<u64>(&db, &new_root, &old_root).unwrap();
+        assert_eq!(changes, vec![
+            Change::Removed(H256::from(5u64), 5),
+            Change::Changed(H256::from(3 * 0x1001u64), 1000, 3),
+            Change::Added(H256::from(7 * 0x1001u64), 7),
+        ]);
+        assert_eq!(diff::<u64>(&db, &new_root, &new_root).unwrap(), vec![]);
+
+        let changes = diff::<u64>(&db, &EMPTY_ROOT, &old_root).unwrap();
+        assert_eq!(changes.len(), 200);
+        for (index, change) in changes.iter().enumerate() {
+            assert_eq!(*change, Change::Added(H256::from(index as u64 * 0x1001), index as u64));
+        }
+    }
+
+    #[test]
+    fn skip_equal_subtrees_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        // 16 subtrees under the root, 4 keys each
+        for index in 0..64 {
+            let mut key = H256::zero();
+            key[0] = index as u8 * 4;
+            tree.update(&key, Some(index)).unwrap();
+        }
+        let old_root = tree.commit();
+        tree.update(&H256::from(0u64), Some(100)).unwrap();
+        let new_root = tree.commit();
+
+        // delete the subtrees both roots share, diff must not need them
+        let old_children: HashSet<_> = child_hashes(&db.get_value(&old_root).unwrap()[..]).into_iter().collect();
+        let new_children: HashSet<_> = child_hashes(&db.get_value(&new_root).unwrap()[..]).into_iter().collect();
+        let shared: Vec<_> = old_children.intersection(&new_children).cloned().collect();
+        assert!(!shared.is_empty());
+        db.delete_nodes(&shared);
+
+        let changes = diff::<u64>(&db, &old_root, &new_root).unwrap();
+        assert_eq!(changes, vec![Change::Changed(H256::from(0u64), 0, 100)]);
+        assert_eq!(*changes[0].key(), H256::from(0u64));
+    }
+
+    #[test]
+    fn short_key_test() {
+        let db = Database::in_memory();
+        // leaf with the odd key [1] right at the root
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&vec![0x31u8]).append(&5u64);
+        let data = stream.out();
+        let root = keccak(&data);
+        db.insert_nodes(&vec![(root, data)], &root);
+
+        assert_eq!(diff::<u64>(&db, &EMPTY_ROOT, &root), Err(TrieError::InvalidNode(root, vec![1])));
+        assert_eq!(diff::<u64>(&db, &root, &EMPTY_ROOT), Err(TrieError::InvalidNode(root, vec![1])));
+    }
+
+    #[test]
+    fn missing_root_test() {
+        let db = Database::in_memory();
+        let unknown = H256::from(42u64);
+
+        assert_eq!(diff::<u64>(&db, &EMPTY_ROOT, &unknown), Err(TrieError::MissingNode(unknown)));
+    }
+}
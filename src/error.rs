@@ -0,0 +1,173 @@
+use ethereum_types::H256;
+use rlp::DecoderError;
+use std::fmt;
+use std::error::Error as StdError;
+
+#[derive(Debug, PartialEq, Eq)]
+/// Error returned by tree operations.
+pub enum TrieError {
+    /// Node with this hash is not in the database, e.g. it was pruned.
+    MissingNode(H256),
+    /// Node with this hash can't be decoded. The path holds the nibbles from
+    /// the root of its tree to the node.
+    InvalidNode(H256, Vec<u8>),
+    /// Root isn't known, e.g. it is older than the journal's reorg window.
+    UnknownRoot(H256),
+    /// Node stored or supplied for this hash hashes to something else, path
+    /// as for `InvalidNode`.
+    HashMismatch(H256, Vec<u8>),
+    /// Contract code with this hash is not in the database.
+    MissingCode(H256),
+    /// Value stored under this key, hashed in secure trees, can't be
+    /// decoded, e.g. an account that isn't an RLP account.
+    InvalidValue(H256),
+}
+
+impl StdError for TrieError {
+    fn description(&self) -> &str {
+        match *self {
+            TrieError::MissingNode(_) => "missing trie node",
+            TrieError::InvalidNode(_, _) => "invalid trie node",
+            TrieError::UnknownRoot(_) => "unknown root",
+            TrieError::HashMismatch(_, _) => "node hash mismatch",
+            TrieError::MissingCode(_) => "missing code",
+            TrieError::InvalidValue(_) => "invalid value",
+        }
+    }
+}
+
+impl fmt::Display for TrieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrieError::MissingNode(ref hash) => write!(f, "missing trie node {:?}", hash),
+            TrieError::InvalidNode(ref hash, ref path) => write!(f, "invalid trie node {:?} at path {:?}", hash, path),
+            TrieError::UnknownRoot(ref hash) => write!(f, "unknown root {:?}", hash),
+            TrieError::HashMismatch(ref hash, ref path) => write!(f, "node at path {:?} doesn't hash to {:?}", path, hash),
+            TrieError::MissingCode(ref hash) => write!(f, "missing code {:?}", hash),
+            TrieError::InvalidValue(ref key) => write!(f, "invalid value at key {:?}", key),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+/// Reason an untrusted node was rejected by the strict decoder.
+pub enum DecodeError {
+    /// Data isn't valid RLP or a value can't be decoded.
+    Rlp(DecoderError),
+    /// Data continues after the node.
+    TrailingBytes,
+    /// Node is a list of this many items, only 2 and 17 are valid.
+    ItemCount(usize),
+    /// Hex-prefix flag nibble above 3.
+    InvalidFlags(u8),
+    /// Padding nibble of an even length key isn't zero.
+    NonZeroPadding,
+    /// Key has no hex-prefix byte, or an extension has no nibbles.
+    EmptyKey,
+    /// Child reference of this many bytes: only empty references, 32 byte
+    /// hashes and nodes shorter than 32 bytes are valid.
+    ChildSize(usize),
+}
+
+impl From<DecoderError> for DecodeError {
+    fn from(error: DecoderError) -> DecodeError {
+        DecodeError::Rlp(error)
+    }
+}
+
+impl StdError for DecodeError {
+    fn description(&self) -> &str {
+        match *self {
+            DecodeError::Rlp(_) => "invalid rlp",
+            DecodeError::TrailingBytes => "trailing bytes after node",
+            DecodeError::ItemCount(_) => "invalid node item count",
+            DecodeError::InvalidFlags(_) => "invalid hex-prefix flags",
+            DecodeError::NonZeroPadding => "nonzero hex-prefix padding",
+            DecodeError::EmptyKey => "empty node key",
+            DecodeError::ChildSize(_) => "invalid child reference size",
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Rlp(ref error) => write!(f, "invalid rlp: {}", error),
+            DecodeError::ItemCount(count) => write!(f, "node with {} items", count),
+            DecodeError::InvalidFlags(flags) => write!(f, "hex-prefix flags {}", flags),
+            DecodeError::ChildSize(size) => write!(f, "child reference of {} bytes", size),
+            DecodeError::TrailingBytes => write!(f, "trailing bytes after node"),
+            DecodeError::NonZeroPadding => write!(f, "nonzero hex-prefix padding"),
+            DecodeError::EmptyKey => write!(f, "empty node key"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+/// Reason an account proof, e.g. an `eth_getProof` response, was rejected.
+pub enum ProofError {
+    /// Text isn't valid JSON or a field is missing or malformed.
+    Json(String),
+    /// A node on the path isn't in the proof or can't be decoded.
+    Trie(TrieError),
+    /// Account fields differ from the ones the account proof proves.
+    AccountMismatch,
+    /// Value of this storage key differs from the one its proof proves.
+    StorageMismatch(H256),
+}
+
+impl From<TrieError> for ProofError {
+    fn from(error: TrieError) -> ProofError {
+        ProofError::Trie(error)
+    }
+}
+
+impl StdError for ProofError {
+    fn description(&self) -> &str {
+        match *self {
+            ProofError::Json(_) => "invalid proof json",
+            ProofError::Trie(_) => "invalid proof",
+            ProofError::AccountMismatch => "account doesn't match its proof",
+            ProofError::StorageMismatch(_) => "storage value doesn't match its proof",
+        }
+    }
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProofError::Json(ref error) => write!(f, "invalid proof json: {}", error),
+            ProofError::Trie(ref error) => write!(f, "invalid proof: {}", error),
+            ProofError::AccountMismatch => write!(f, "account doesn't match its proof"),
+            ProofError::StorageMismatch(ref key) => write!(f, "storage value of {:?} doesn't match its proof", key),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+/// Reason a database can't be opened with this version's key layout.
+pub enum SchemaError {
+    /// Database was created with this schema version.
+    UnsupportedVersion(u32),
+    /// Database has no schema version and isn't empty: it was written before
+    /// columns existed and has to be rebuilt.
+    LegacyLayout,
+}
+
+impl StdError for SchemaError {
+    fn description(&self) -> &str {
+        match *self {
+            SchemaError::UnsupportedVersion(_) => "unsupported schema version",
+            SchemaError::LegacyLayout => "pre-column database layout",
+        }
+    }
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchemaError::UnsupportedVersion(version) => write!(f, "unsupported database schema version {}", version),
+            SchemaError::LegacyLayout => write!(f, "database has the pre-column layout, rebuild it"),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use ethereum_types::H256;
+use rlp::{Decodable, UntrustedRlp};
+use node::*;
+use hex_prefix;
+use db::*;
+use std::collections::HashSet;
+
+/// What is wrong with a node
+#[derive(Debug, PartialEq)]
+pub enum ProblemKind {
+    /// Referenced node isn't stored
+    Missing,
+    /// Stored data doesn't hash to its key
+    HashMismatch,
+    /// Stored data isn't a valid node, or one of its inlined children isn't
+    InvalidNode,
+    /// Node of this many bytes is inlined, only nodes under 32 bytes may be
+    InlinedTooLong(usize),
+    /// Node of this many bytes is stored by hash, it should be inlined
+    HashedTooShort(usize),
+    /// Full node with less than two children, value included
+    SingleChildBranch,
+    /// Extension with an empty key
+    EmptyExtension,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    /// Nibbles from the root to the node
+    pub path: Vec<u8>,
+    /// Stored node the problem was found in, inlined nodes are part of
+    /// their parent
+    pub hash: H256,
+    pub kind: ProblemKind,
+}
+
+/// Outcome of an integrity check
+#[derive(Debug, PartialEq)]
+pub struct FsckReport {
+    pub nodes_checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks every node reachable from `root`: that it is stored, hashes to its
+/// key, decodes and is encoded the canonical way. Nodes shared by several
+/// paths are checked once and reported with the first path that reaches
+/// them. Children of a node that can't be read are not checked.
+pub fn fsck<T: Decodable>(db: &Database, root: &H256) -> FsckReport {
+    let mut report = FsckReport {
+        nodes_checked: 0,
+        problems: Vec::new(),
+    };
+    if *root == EMPTY_ROOT {
+        return report
+    }
+    let mut checked = HashSet::new();
+    let mut stack = vec![(*root, Vec::new())];
+
+    while let Some((hash, path)) = stack.pop() {
+        if !checked.insert(hash) {
+            continue;
+        }
+        let data = match db.get_value(&hash) {
+            Some(data) => data,
+            None => {
+                report.problems.push(Problem {path, hash, kind: ProblemKind::Missing});
+                continue;
+            }
+        };
+        report.nodes_checked += 1;
+
+        if keccak(&data[..]) != hash {
+            report.problems.push(Problem {path, hash, kind: ProblemKind::HashMismatch});
+            continue;
+        }
+        // the root is stored by hash whatever its size
+        if data.len() < 32 && hash != *root {
+            report.problems.push(Problem {path: path.clone(), hash, kind: ProblemKind::HashedTooShort(data.len())});
+        }
+        let mut children = Vec::new();
+        let mut problems = Vec::new();
+
+        // the strict decoder also rejects the non-canonical encodings, which
+        // are reported on their own
+        let valid = check_node(UntrustedRlp::new(&data[..]), &path, &mut children, &mut problems)
+            && (!problems.is_empty() || decode_node_strict::<T>(&hash, &data[..]).is_ok());
+        if !valid {
+            report.problems.push(Problem {path, hash, kind: ProblemKind::InvalidNode});
+            continue;
+        }
+        for (path, kind) in problems {
+            report.problems.push(Problem {path, hash, kind});
+        }
+        stack.extend(children);
+    }
+    report
+}
+
+// Checks the structure of an encoded node and collects the hashes of its
+// children. Returns false if the node isn't a valid full or short node.
+fn check_node(rlp: UntrustedRlp, path: &[u8], children: &mut Vec<(H256, Vec<u8>)>,
+    problems: &mut Vec<(Vec<u8>, ProblemKind)>) -> bool
+{
+    match rlp.item_count() {
+        Ok(17) => {
+            let mut used = 0;
+
+            for index in 0..17 {
+                match rlp.at(index) {
+                    Ok(ref item) if item.is_empty() => {},
+                    Ok(_) => used += 1,
+                    Err(_) => return false,
+                }
+            }
+            if used < 2 {
+                problems.push((path.to_vec(), ProblemKind::SingleChildBranch));
+            }
+            for index in 0..16 {
+                let mut child_path = path.to_vec();
+                child_path.push(index as u8);
+
+                if !check_ref(rlp.at(index).unwrap(), &child_path, children, problems) {
+                    return false
+                }
+            }
+            true
+        },
+        Ok(2) => {
+            let encoded_key = match rlp.val_at::<Vec<u8>>(0) {
+                Ok(key) => key,
+                Err(_) => return false,
+            };
+            let (key, is_leaf) = match hex_prefix::decode(&encoded_key[..]) {
+                Ok(key) => key,
+                Err(_) => return false,
+            };
+            // leaf values hold no references
+            if is_leaf {
+                return true
+            }
+            if key.is_empty() {
+                problems.push((path.to_vec(), ProblemKind::EmptyExtension));
+            }
+            let mut child_path = path.to_vec();
+            child_path.extend(key.iter());
+            match rlp.at(1) {
+                Ok(item) => check_ref(item, &child_path, children, problems),
+                Err(_) => false,
+            }
+        },
+        _ => false,
+    }
+}
+
+fn check_ref(rlp: UntrustedRlp, path: &[u8], children: &mut Vec<(H256, Vec<u8>)>,
+    problems: &mut Vec<(Vec<u8>, ProblemKind)>) -> bool
+{
+    if rlp.is_list() {
+        let size = rlp.as_raw().len();
+        if size >= 32 {
+            problems.push((path.to_vec(), ProblemKind::InlinedTooLong(size)));
+        }
+        return check_node(rlp, path, children, problems)
+    }
+    let data = match rlp.data() {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+    match data.len() {
+        0 => true,
+        32 => {
+            children.push((H256::from_slice(data), path.to_vec()));
+            true
+        },
+        // inlined node wrapped in a byte string, as written by older versions
+        size => {
+            if size >= 32 {
+                problems.push((path.to_vec(), ProblemKind::InlinedTooLong(size)));
+            }
+            check_node(UntrustedRlp::new(data), path, children, problems)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::MerkleTree;
+    use nibble::NibbleVec;
+    use rlp::RlpStream;
+
+    fn store(db: &Database, data: Vec<u8>) -> H256 {
+        let hash = keccak(&data[..]);
+        db.set_value(&hash, &data);
+        hash
+    }
+
+    fn leaf(key: &[u8], value: u64) -> Vec<u8> {
+        let mut nibbles = NibbleVec::new();
+        for &nibble in key {
+            nibbles.push(nibble);
+        }
+        let mut rlp_s = RlpStream::new_list(2);
+        rlp_s.append(&hex_prefix::encode(&nibbles.as_slice(), true)).append(&value);
+        rlp_s.out()
+    }
+
+    // Full node with the given child references
+    fn branch(refs: &[(usize, &H256)]) -> Vec<u8> {
+        let mut rlp_s = RlpStream::new_list(17);
+        for index in 0..17 {
+            match refs.iter().find(|&&(position, _)| position == index) {
+                Some(&(_, hash)) => { rlp_s.append(hash); },
+                None => { rlp_s.append_empty_data(); },
+            }
+        }
+        rlp_s.out()
+    }
+
+    #[test]
+    fn fsck_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..100 {
+            tree.update(&keccak(&[index as u8]), Some(index)).unwrap();
+        }
+        let root = tree.commit();
+        let mut nodes = 0;
+        db.for_each_node(|_, _| nodes += 1);
+
+        let report = fsck::<u64>(&db, &root);
+        assert!(report.is_ok());
+        assert_eq!(report.nodes_checked, nodes);
+        assert!(fsck::<u64>(&db, &EMPTY_ROOT).is_ok());
+
+        let children = child_hashes(&db.get_value(&root).unwrap()[..]);
+        let corrupt = children[0];
+        let mut data = db.get_value(&corrupt).unwrap();
+        data[3] ^= 0x40;
+        db.set_value(&corrupt, &data);
+        db.delete_value(&children[1]);
+
+        let report = fsck::<u64>(&db, &root);
+        assert_eq!(report.problems.len(), 2);
+        // both are children of the root full node
+        for problem in report.problems.iter() {
+            assert_eq!(problem.path.len(), 1);
+            if problem.hash == corrupt {
+                assert_eq!(problem.kind, ProblemKind::HashMismatch);
+            } else {
+                assert_eq!(problem.hash, children[1]);
+                assert_eq!(problem.kind, ProblemKind::Missing);
+            }
+        }
+    }
+
+    #[test]
+    fn non_canonical_test() {
+        let db = Database::in_memory();
+        let long_key = [0; 63];
+
+        // a leaf this short should have been inlined into its parent
+        let short_leaf = store(&db, leaf(&[0x05], 1));
+        let long_leaf = store(&db, leaf(&long_key[..], 2));
+        let single = store(&db, branch(&[(3, &long_leaf)]));
+        let mut rlp_s = RlpStream::new_list(2);
+        // compact encoding of an empty extension key
+        rlp_s.append(&vec![0x00u8]).append(&single);
+        let extension = store(&db, rlp_s.out());
+
+        let mut rlp_s = RlpStream::new_list(17);
+        rlp_s.append(&short_leaf).append(&extension);
+        // inlined leaf that is too long
+        rlp_s.append_raw(&leaf(&long_key[..], 3)[..], 1);
+        for _ in 3..17 {
+            rlp_s.append_empty_data();
+        }
+        let root = store(&db, rlp_s.out());
+
+        let report = fsck::<u64>(&db, &root);
+        assert_eq!(report.nodes_checked, 5);
+        let mut problems = report.problems;
+        problems.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(problems, vec![
+            Problem {path: vec![0], hash: short_leaf, kind: ProblemKind::HashedTooShort(leaf(&[0x05], 1).len())},
+            Problem {path: vec![1], hash: extension, kind: ProblemKind::EmptyExtension},
+            Problem {path: vec![1], hash: single, kind: ProblemKind::SingleChildBranch},
+            Problem {path: vec![2], hash: root, kind: ProblemKind::InlinedTooLong(leaf(&long_key[..], 3).len())},
+        ]);
+    }
+
+    #[test]
+    fn invalid_node_test() {
+        let db = Database::in_memory();
+        let mut rlp_s = RlpStream::new_list(3);
+        rlp_s.append(&1u64).append(&2u64).append(&3u64);
+        let root = store(&db, rlp_s.out());
+
+        let report = fsck::<u64>(&db, &root);
+        assert_eq!(report.problems, vec![Problem {path: vec![], hash: root, kind: ProblemKind::InvalidNode}]);
+
+        // a well formed leaf whose value isn't a u64
+        let mut rlp_s = RlpStream::new_list(2);
+        rlp_s.append(&vec![0x20u8, 0x01]).append(&vec![7u8; 40]);
+        let root = store(&db, rlp_s.out());
+        assert!(fsck::<Vec<u8>>(&db, &root).is_ok());
+        let report = fsck::<u64>(&db, &root);
+        assert_eq!(report.problems, vec![Problem {path: vec![], hash: root, kind: ProblemKind::InvalidNode}]);
+    }
+
+    #[test]
+    fn invalid_inline_child_test() {
+        let db = Database::in_memory();
+        let long_leaf = store(&db, leaf(&[0; 63], 1));
+        // inlined leaf whose value is too long for a u64
+        let mut rlp_s = RlpStream::new_list(2);
+        rlp_s.append(&vec![0x3du8]).append(&vec![7u8; 9]);
+        let child = rlp_s.out();
+
+        let mut rlp_s = RlpStream::new_list(17);
+        rlp_s.append(&long_leaf).append_raw(&child[..], 1);
+        for _ in 2..17 {
+            rlp_s.append_empty_data();
+        }
+        let root = store(&db, rlp_s.out());
+
+        assert!(fsck::<Vec<u8>>(&db, &root).is_ok());
+        let report = fsck::<u64>(&db, &root);
+        assert_eq!(report.problems, vec![Problem {path: vec![], hash: root, kind: ProblemKind::InvalidNode}]);
+    }
+}
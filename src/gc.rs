@@ -0,0 +1,274 @@
+use ethereum_types::H256;
+use node::*;
+use db::*;
+use state::{Account, KECCAK_EMPTY};
+use std::collections::HashSet;
+
+// Nodes or code deleted in one batch, so a sweep of a large database doesn't
+// hold all of its garbage at once
+const SWEEP_BATCH_SIZE: usize = 10000;
+
+/// Outcome of a garbage collection run
+#[derive(Debug, PartialEq)]
+pub struct GcReport {
+    pub nodes_kept: usize,
+    pub nodes_deleted: usize,
+    pub codes_kept: usize,
+    pub codes_deleted: usize,
+    /// Key and value bytes of the deleted nodes and code
+    pub bytes_reclaimed: usize,
+}
+
+/// Offline mark and sweep: keeps every node reachable from `roots` and deletes
+/// all other nodes. Unlike `Database::prune_root` it doesn't need reference
+/// counts, so it also compacts databases written before pruning existed.
+/// No tree may commit to the database while it runs, new nodes that aren't
+/// reachable from `roots` would be swept. Stored code is left alone.
+pub fn collect_garbage(db: &Database, roots: &[H256]) -> GcReport {
+    let marked = mark(db, roots);
+    sweep(db, &marked, None)
+}
+
+/// Same as `collect_garbage` for state roots: the storage tries and code of
+/// the accounts are kept as well, and code no account refers to is deleted.
+pub fn collect_state_garbage(db: &Database, state_roots: &[H256]) -> GcReport {
+    let (marked, codes) = mark_state(db, state_roots);
+    sweep(db, &marked, Some(&codes))
+}
+
+// Deleted nodes release their references on the kept nodes, so the kept
+// reference counts stay right for later prunes
+fn sweep(db: &Database, marked: &HashSet<H256>, codes: Option<&HashSet<H256>>) -> GcReport {
+    let mut garbage = Vec::new();
+    let mut nodes_deleted = 0;
+    let mut codes_deleted = 0;
+    let mut bytes_reclaimed = 0;
+
+    db.for_each_node(|hash, data| {
+        if !marked.contains(hash) {
+            garbage.push(*hash);
+            bytes_reclaimed += hash.len() + data.len();
+        }
+        if garbage.len() == SWEEP_BATCH_SIZE {
+            db.delete_nodes(&garbage);
+            nodes_deleted += garbage.len();
+            garbage.clear();
+        }
+    });
+    db.delete_nodes(&garbage);
+    nodes_deleted += garbage.len();
+    garbage.clear();
+
+    if let Some(codes) = codes {
+        db.for_each_code(|hash, code| {
+            if !codes.contains(hash) {
+                garbage.push(*hash);
+                bytes_reclaimed += hash.len() + code.len();
+            }
+            if garbage.len() == SWEEP_BATCH_SIZE {
+                db.delete_code(&garbage);
+                codes_deleted += garbage.len();
+                garbage.clear();
+            }
+        });
+        db.delete_code(&garbage);
+        codes_deleted += garbage.len();
+    }
+
+    GcReport {
+        nodes_kept: marked.len(),
+        nodes_deleted,
+        codes_kept: codes.map_or(0, |codes| codes.len()),
+        codes_deleted,
+        bytes_reclaimed,
+    }
+}
+
+fn mark(db: &Database, roots: &[H256]) -> HashSet<H256> {
+    let mut marked = HashSet::new();
+    let mut stack = roots.to_vec();
+
+    while let Some(hash) = stack.pop() {
+        if marked.contains(&hash) {
+            continue;
+        }
+        // missing nodes, e.g. the empty root, have nothing to keep
+        if let Some(data) = db.get_value(&hash) {
+            stack.extend(child_hashes(&data[..]));
+            marked.insert(hash);
+        }
+    }
+    marked
+}
+
+// Nodes of the account tries and the storage tries of their accounts, and
+// the stored code of the accounts
+fn mark_state(db: &Database, state_roots: &[H256]) -> (HashSet<H256>, HashSet<H256>) {
+    let mut marked = HashSet::new();
+    let mut codes = HashSet::new();
+    let mut storage_roots = Vec::new();
+    let mut stack = state_roots.to_vec();
+
+    while let Some(hash) = stack.pop() {
+        if marked.contains(&hash) {
+            continue;
+        }
+        if let Some(data) = db.get_value(&hash) {
+            stack.extend(child_hashes(&data[..]));
+            for account in Account::from_node(&data[..]) {
+                storage_roots.push(account.storage_root);
+                if account.code_hash != KECCAK_EMPTY && db.get_code(&account.code_hash).is_some() {
+                    codes.insert(account.code_hash);
+                }
+            }
+            marked.insert(hash);
+        }
+    }
+    // storage leaves hold plain values, so their nodes are marked like any tree
+    let mut stack = storage_roots;
+    while let Some(hash) = stack.pop() {
+        if marked.contains(&hash) {
+            continue;
+        }
+        if let Some(data) = db.get_value(&hash) {
+            stack.extend(child_hashes(&data[..]));
+            marked.insert(hash);
+        }
+    }
+    (marked, codes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::MerkleTree;
+    use state::StateDB;
+    use ethereum_types::{Address, U256};
+
+    #[test]
+    fn collect_garbage_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let mut roots = Vec::new();
+
+        for round in 0..10 {
+            for index in 0..20 {
+                tree.update(&H256::from(index * 0x10001u64), Some(round * index)).unwrap();
+            }
+            roots.push(tree.commit());
+        }
+        let mut stored = 0;
+        let mut stored_bytes = 0;
+        db.for_each_node(|hash, data| {
+            stored += 1;
+            stored_bytes += hash.len() + data.len();
+        });
+        let keep = [roots[4], roots[9]];
+        let kept = mark(&db, &keep);
+
+        let report = collect_garbage(&db, &keep);
+        assert_eq!(report.nodes_kept, kept.len());
+        assert_eq!(report.nodes_deleted, stored - kept.len());
+
+        let mut left = 0;
+        let mut left_bytes = 0;
+        db.for_each_node(|hash, data| {
+            assert!(kept.contains(hash));
+            left += 1;
+            left_bytes += hash.len() + data.len();
+        });
+        assert_eq!(left, kept.len());
+        assert_eq!(report.bytes_reclaimed, stored_bytes - left_bytes);
+
+        for &(round, root) in [(4, roots[4]), (9, roots[9])].iter() {
+            let tree = MerkleTree::<u64>::at_root(db.clone(), root).unwrap();
+            for index in 0..20 {
+                assert_eq!(tree.get(&H256::from(index * 0x10001u64)).unwrap(), Some(round * index));
+            }
+        }
+        assert!(MerkleTree::<u64>::at_root(db.clone(), roots[5]).is_err());
+        // everything left is reachable
+        assert_eq!(collect_garbage(&db, &keep).nodes_deleted, 0);
+    }
+
+    #[test]
+    fn collect_everything_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..20 {
+            tree.update(&H256::from(index), Some(index)).unwrap();
+        }
+        let root = tree.commit();
+        let report = collect_garbage(&db, &[EMPTY_ROOT]);
+
+        assert_eq!(report.nodes_kept, 0);
+        assert!(report.nodes_deleted > 0);
+        assert_eq!(db.get_value(&root), None);
+        assert_eq!(db.ref_count(&root), 0);
+    }
+
+    #[test]
+    fn released_references_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..50 {
+            tree.update(&H256::from(index * 0x1000001u64), Some(index)).unwrap();
+        }
+        let old_root = tree.commit();
+        tree.update(&H256::from(0u64), Some(100)).unwrap();
+        let root = tree.commit();
+
+        // the swept path of the old root shared children with the kept root
+        assert!(collect_garbage(&db, &[root]).nodes_deleted > 0);
+        assert_eq!(db.get_value(&old_root), None);
+        let tree = MerkleTree::<u64>::at_root(db.clone(), root).unwrap();
+        assert_eq!(tree.get(&H256::from(0x1000001u64)).unwrap(), Some(1));
+
+        db.prune_root(&root);
+        db.for_each_node(|_, _| panic!("pruned node left behind"));
+    }
+
+    #[test]
+    fn collect_state_garbage_test() {
+        let db = Database::in_memory();
+        let mut state = StateDB::new(db.clone(), EMPTY_ROOT).unwrap();
+
+        state.set_code(&Address::from(1), vec![1, 2, 3]).unwrap();
+        state.set_code(&Address::from(2), vec![4, 5, 6]).unwrap();
+        for index in 0..20 {
+            state.set_storage(&Address::from(1), &H256::from(index), U256::from(index + 1)).unwrap();
+            state.set_balance(&Address::from(index + 10), U256::from(index)).unwrap();
+        }
+        let first = state.commit().unwrap();
+        state.set_code(&Address::from(2), vec![7]).unwrap();
+        state.set_storage(&Address::from(1), &H256::from(0u64), U256::from(100)).unwrap();
+        let second = state.commit().unwrap();
+
+        // both states are kept whole
+        let report = collect_state_garbage(&db, &[first, second]);
+        assert_eq!(report.nodes_deleted, 0);
+        assert_eq!(report.codes_kept, 3);
+        assert_eq!(report.codes_deleted, 0);
+
+        let report = collect_state_garbage(&db, &[second]);
+        assert!(report.nodes_deleted > 0);
+        assert_eq!(report.codes_kept, 2);
+        assert_eq!(report.codes_deleted, 1);
+        assert_eq!(db.get_code(&keccak(&[4, 5, 6])), None);
+
+        let state = StateDB::new(db.clone(), second).unwrap();
+        assert_eq!(state.get_code(&Address::from(1)).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(state.get_code(&Address::from(2)).unwrap(), Some(vec![7]));
+        assert_eq!(state.get_storage(&Address::from(1), &H256::from(0u64)).unwrap(), U256::from(100));
+        for index in 1..20 {
+            assert_eq!(state.get_storage(&Address::from(1), &H256::from(index)).unwrap(), U256::from(index + 1));
+            assert_eq!(state.balance(&Address::from(index + 10)).unwrap(), U256::from(index));
+        }
+        assert_eq!(collect_state_garbage(&db, &[second]).nodes_deleted, 0);
+        // storage nodes aren't reachable through the account trie alone
+        assert!(collect_garbage(&db, &[second]).nodes_deleted > 0);
+        assert!(state.get_storage(&Address::from(1), &H256::from(0u64)).is_err());
+    }
+}
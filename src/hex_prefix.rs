@@ -0,0 +1,118 @@
+use nibble::NibbleSlice;
+use error::DecodeError;
+
+/// Hex-prefix encoding of a path: a flag nibble for leaf keys and odd
+/// lengths, a zero padding nibble for even lengths, then the packed path.
+/// The empty path encodes to the flag byte alone.
+pub fn encode(nibbles: &NibbleSlice, is_leaf: bool) -> Vec<u8> {
+    let odd_len = nibbles.len() % 2;
+    let flags = if is_leaf {2} else {0} + odd_len as u8;
+    let mut result = Vec::with_capacity(nibbles.len() / 2 + 1);
+
+    if odd_len == 1 {
+        result.push(flags << 4 | nibbles.at(0));
+    } else {
+        result.push(flags << 4);
+    }
+    for index in (odd_len..nibbles.len()).step_by(2) {
+        result.push(nibbles.at(index) << 4 | nibbles.at(index + 1));
+    }
+    result
+}
+
+/// Path and leaf flag of an encoded key, the path is a view into `data`.
+/// Only the output of `encode` is accepted, so a decoded key encodes back
+/// to the same bytes.
+pub fn decode<'a>(data: &'a [u8]) -> Result<(NibbleSlice<'a>, bool), DecodeError> {
+    let first = match data.first() {
+        Some(&first) => first,
+        None => return Err(DecodeError::EmptyKey),
+    };
+    let flags = first >> 4;
+
+    if flags > 3 {
+        return Err(DecodeError::InvalidFlags(flags))
+    }
+    let odd_len = flags & 1 == 1;
+    if !odd_len && first & 0x0F != 0 {
+        return Err(DecodeError::NonZeroPadding)
+    }
+    // skip the flag nibble, and the padding nibble of even paths
+    let path = NibbleSlice::new(data).mid(if odd_len {1} else {2});
+    Ok((path, flags & 2 == 2))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use super::*;
+    use nibble::NibbleVec;
+    use self::rand::Rng;
+
+    fn nibbles(values: &[u8]) -> NibbleVec {
+        let mut result = NibbleVec::new();
+        for &value in values {
+            result.push(value);
+        }
+        result
+    }
+
+    #[test]
+    fn encode_test() {
+        // [ 0x01, 0x02, 0x03, 0x04, 0x05 ] -> [ 0x11, 0x23, 0x45 ]
+        assert_eq!(encode(&nibbles(&[1, 2, 3, 4, 5]).as_slice(), false), vec![0x11, 0x23, 0x45]);
+        // [ 0x00, 0x01, 0x02, 0x03, 0x04, 0x05 ] -> [ 0x00, 0x01, 0x23, 0x45 ]
+        assert_eq!(encode(&nibbles(&[0, 1, 2, 3, 4, 5]).as_slice(), false), vec![0x00, 0x01, 0x23, 0x45]);
+        // leaf [ 0x00, 0x0f, 0x01, 0x0c, 0x0b, 0x08 ] -> [ 0x20, 0x0f, 0x1c, 0xb8 ]
+        assert_eq!(encode(&nibbles(&[0, 0xf, 1, 0xc, 0xb, 8]).as_slice(), true), vec![0x20, 0x0f, 0x1c, 0xb8]);
+        // leaf [ 0x0f, 0x01, 0x0c, 0x0b, 0x08 ] -> [ 0x3f, 0x1c, 0xb8 ]
+        assert_eq!(encode(&nibbles(&[0xf, 1, 0xc, 0xb, 8]).as_slice(), true), vec![0x3f, 0x1c, 0xb8]);
+        assert_eq!(encode(&NibbleVec::new().as_slice(), false), vec![0x00]);
+        assert_eq!(encode(&NibbleVec::new().as_slice(), true), vec![0x20]);
+    }
+
+    #[test]
+    fn decode_test() {
+        assert_eq!(decode(&[0x11, 0x23, 0x45]), Ok((nibbles(&[1, 2, 3, 4, 5]).as_slice(), false)));
+        assert_eq!(decode(&[0x00, 0x01, 0x23, 0x45]), Ok((nibbles(&[0, 1, 2, 3, 4, 5]).as_slice(), false)));
+        assert_eq!(decode(&[0x20, 0x0f, 0x1c, 0xb8]), Ok((nibbles(&[0, 0xf, 1, 0xc, 0xb, 8]).as_slice(), true)));
+        assert_eq!(decode(&[0x3f, 0x1c, 0xb8]), Ok((nibbles(&[0xf, 1, 0xc, 0xb, 8]).as_slice(), true)));
+        assert_eq!(decode(&[0x00]), Ok((NibbleVec::new().as_slice(), false)));
+        assert_eq!(decode(&[0x20]), Ok((NibbleVec::new().as_slice(), true)));
+    }
+
+    #[test]
+    fn invalid_test() {
+        assert_eq!(decode(&[]), Err(DecodeError::EmptyKey));
+        assert_eq!(decode(&[0x40, 0x12]), Err(DecodeError::InvalidFlags(4)));
+        assert_eq!(decode(&[0xf1]), Err(DecodeError::InvalidFlags(0xf)));
+        assert_eq!(decode(&[0x01, 0x23]), Err(DecodeError::NonZeroPadding));
+        assert_eq!(decode(&[0x2f]), Err(DecodeError::NonZeroPadding));
+    }
+
+    #[test]
+    fn round_trip_fuzz_test() {
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..10000 {
+            let len = rng.gen_range(0, 80);
+            let path: Vec<u8> = (0..len).map(|_| rng.gen_range(0, 16)).collect();
+            let is_leaf = rng.gen();
+
+            let encoded = encode(&nibbles(&path[..]).as_slice(), is_leaf);
+            let (decoded, decoded_leaf) = decode(&encoded[..]).unwrap();
+            assert_eq!(decoded.iter().collect::<Vec<u8>>(), path);
+            assert_eq!(decoded_leaf, is_leaf);
+        }
+        // arbitrary bytes either fail or are the encoding of what they decode to
+        for _ in 0..10000 {
+            let len = rng.gen_range(0, 40);
+            let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+
+            if let Ok((path, is_leaf)) = decode(&data[..]) {
+                assert_eq!(encode(&path, is_leaf), data);
+            }
+        }
+    }
+}
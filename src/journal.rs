@@ -0,0 +1,214 @@
+use ethereum_types::H256;
+use rlp::{Encodable, Decodable};
+use db::*;
+use error::TrieError;
+use tree::MerkleTree;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+struct JournalEntry {
+    root: H256,
+    // undo logs of the commit and of the prune it triggered, in write order
+    undo: Vec<UndoLog>,
+    // root that went out of the window with this commit
+    pruned: Option<H256>,
+}
+
+/// Commits trees while keeping only the most recent roots, and remembers how
+/// to undo each commit so chain reorganizations can go back to any of them.
+///
+/// Every commit records the root reference it added. Once more than
+/// `history` roots are kept, the oldest is pruned and the deleted nodes are
+/// recorded with the commit that pruned them, so reverting that commit
+/// brings them back. Reverting releases and adds references rather than
+/// restoring old counts, so trees committed to the same database outside
+/// the journal keep their nodes. The journal lives in memory.
+pub struct Journal {
+    db: Arc<Database>,
+    history: usize,
+    entries: VecDeque<JournalEntry>,
+}
+
+impl Journal {
+    pub fn new(db: Arc<Database>, history: usize) -> Journal {
+        assert!(history > 0, "journal has to keep at least one root");
+        Journal {
+            db,
+            history,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Roots that can be reverted to, oldest first
+    pub fn roots(&self) -> Vec<H256> {
+        self.entries.iter().map(|entry| entry.root).collect()
+    }
+
+    /// Commits `tree`, which has to use the journal's database, and prunes
+    /// the root that goes out of the window
+    pub fn commit<T: Encodable + Decodable + Clone>(&mut self, tree: &mut MerkleTree<T>) -> H256 {
+        let (root, undo) = tree.commit_with_undo();
+        let mut entry = JournalEntry {
+            root,
+            undo: vec![undo],
+            pruned: None,
+        };
+        if self.entries.len() == self.history {
+            let oldest = self.entries.pop_front().unwrap();
+            let (_, undo) = self.db.prune_root_with_undo(&oldest.root);
+            entry.undo.push(undo);
+            entry.pruned = Some(oldest.root);
+        }
+        self.entries.push_back(entry);
+        root
+    }
+
+    /// Undoes every commit made after `root`, latest first. Nodes those
+    /// commits pruned are restored. Trees opened at the reverted roots have
+    /// to be reopened, e.g. with `MerkleTree::at_root(db, root)`.
+    pub fn revert_to(&mut self, root: &H256) -> Result<(), TrieError> {
+        let position = match self.entries.iter().rposition(|entry| entry.root == *root) {
+            Some(position) => position,
+            None => return Err(TrieError::UnknownRoot(*root)),
+        };
+        for _ in position + 1..self.entries.len() {
+            let entry = self.entries.pop_back().unwrap();
+
+            for undo in entry.undo.iter().rev() {
+                self.db.undo(undo);
+            }
+            // the pruned root is kept again, but can't be reverted past
+            if let Some(pruned) = entry.pruned {
+                self.entries.push_front(JournalEntry {
+                    root: pruned,
+                    undo: Vec::new(),
+                    pruned: None,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gc::collect_garbage;
+    use fsck::fsck;
+    use node::child_hashes;
+    use tree::EMPTY_ROOT;
+
+    fn commit_round(journal: &mut Journal, tree: &mut MerkleTree<u64>, round: u64) -> H256 {
+        tree.update(&H256::from(round % 7), Some(round)).unwrap();
+        tree.update(&H256::from(round * 1000), Some(round)).unwrap();
+        journal.commit(tree)
+    }
+
+    #[test]
+    fn revert_test() {
+        let db = Database::in_memory();
+        let mut journal = Journal::new(db.clone(), 4);
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let mut roots = Vec::new();
+
+        for round in 0..10 {
+            roots.push(commit_round(&mut journal, &mut tree, round));
+        }
+        assert_eq!(journal.roots(), roots[6..].to_vec());
+        journal.revert_to(&roots[7]).unwrap();
+        // the roots pruned by the reverted commits are kept again
+        assert_eq!(journal.roots(), roots[4..8].to_vec());
+        assert!(MerkleTree::<u64>::at_root(db.clone(), roots[4]).is_ok());
+
+        assert!(MerkleTree::<u64>::at_root(db.clone(), roots[8]).is_err());
+        assert!(MerkleTree::<u64>::at_root(db.clone(), roots[9]).is_err());
+        let mut tree = MerkleTree::<u64>::at_root(db.clone(), roots[7]).unwrap();
+        assert_eq!(tree.get(&H256::from(7000u64)).unwrap(), Some(7));
+        assert_eq!(tree.get(&H256::from(8000u64)).unwrap(), None);
+
+        // continue on the new branch
+        let root = commit_round(&mut journal, &mut tree, 20);
+        assert_eq!(journal.roots(), vec![roots[5], roots[6], roots[7], root]);
+        // nothing outside the kept roots is left behind
+        assert_eq!(collect_garbage(&db, &journal.roots()).nodes_deleted, 0);
+    }
+
+    #[test]
+    fn revert_restores_pruned_nodes_test() {
+        let db = Database::in_memory();
+        let mut journal = Journal::new(db.clone(), 3);
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let mut roots = Vec::new();
+
+        for round in 0..5 {
+            roots.push(commit_round(&mut journal, &mut tree, round));
+        }
+        assert!(MerkleTree::<u64>::at_root(db.clone(), roots[0]).is_err());
+        assert!(MerkleTree::<u64>::at_root(db.clone(), roots[1]).is_err());
+
+        journal.revert_to(&roots[2]).unwrap();
+        assert_eq!(journal.roots(), roots[..3].to_vec());
+        for (round, root) in roots.iter().enumerate().take(3) {
+            let tree = MerkleTree::<u64>::at_root(db.clone(), *root).unwrap();
+            assert_eq!(tree.get(&H256::from(round as u64 * 1000)).unwrap(), Some(round as u64));
+        }
+        // the restored roots are pruned again once they leave the window
+        let mut tree = MerkleTree::<u64>::at_root(db.clone(), roots[2]).unwrap();
+        for round in 10..14 {
+            commit_round(&mut journal, &mut tree, round);
+        }
+        assert!(MerkleTree::<u64>::at_root(db.clone(), roots[0]).is_err());
+        assert!(MerkleTree::<u64>::at_root(db.clone(), roots[2]).is_err());
+        assert_eq!(collect_garbage(&db, &journal.roots()).nodes_deleted, 0);
+    }
+
+    #[test]
+    fn revert_keeps_other_commits_test() {
+        let db = Database::in_memory();
+        let mut journal = Journal::new(db.clone(), 2);
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let mut roots = Vec::new();
+
+        for round in 0..4 {
+            roots.push(commit_round(&mut journal, &mut tree, round));
+        }
+        // a tree committed outside the journal, sharing the latest nodes
+        let mut other = tree.clone();
+        other.update(&H256::from(5000u64), Some(5)).unwrap();
+        let other_root = other.commit();
+        let shared = child_hashes(&db.get_value(&roots[3]).unwrap()[..])[0];
+        let count = db.ref_count(&shared);
+
+        journal.revert_to(&roots[2]).unwrap();
+        assert!(fsck::<u64>(&db, &other_root).is_ok());
+        // only the reverted root's reference is gone
+        assert_eq!(db.ref_count(&shared), count - 1);
+        let other = MerkleTree::<u64>::at_root(db.clone(), other_root).unwrap();
+        assert_eq!(other.get(&H256::from(3000u64)).unwrap(), Some(3));
+
+        // releasing every live root leaves nothing behind
+        db.prune_root(&other_root);
+        for root in journal.roots() {
+            db.prune_root(&root);
+        }
+        let mut nodes = 0;
+        db.for_each_node(|_, _| nodes += 1);
+        assert_eq!(nodes, 0);
+    }
+
+    #[test]
+    fn revert_outside_window_test() {
+        let db = Database::in_memory();
+        let mut journal = Journal::new(db.clone(), 2);
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let mut roots = Vec::new();
+
+        for round in 0..4 {
+            roots.push(commit_round(&mut journal, &mut tree, round));
+        }
+        assert_eq!(journal.revert_to(&roots[1]), Err(TrieError::UnknownRoot(roots[1])));
+        // a failed revert changes nothing
+        assert_eq!(journal.roots(), roots[2..].to_vec());
+        assert!(MerkleTree::<u64>::at_root(db.clone(), roots[3]).is_ok());
+    }
+}
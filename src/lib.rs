@@ -2,7 +2,23 @@
 extern crate rlp;
 extern crate exonum_leveldb;
 extern crate ethereum_types;
+extern crate tiny_keccak;
+#[macro_use]
+extern crate serde_json;
 
 pub mod tree;
+pub mod db;
+pub mod error;
+pub mod gc;
+pub mod journal;
+pub mod overlay;
+pub mod diff;
+pub mod sync;
+pub mod fsck;
+pub mod nibble;
+pub mod hex_prefix;
+pub mod secure;
+pub mod state;
+pub mod proof;
+pub mod witness;
 mod node;
-mod db;
\ No newline at end of file
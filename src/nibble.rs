@@ -0,0 +1,239 @@
+use std::fmt;
+use hex_prefix;
+use error::DecodeError;
+
+/// Nibbles of a byte slice, high nibble first, viewed without copying
+#[derive(Clone, Copy)]
+pub struct NibbleSlice<'a> {
+    data: &'a [u8],
+    // nibbles of `data` the slice covers
+    start: usize,
+    end: usize,
+}
+
+impl<'a> NibbleSlice<'a> {
+    pub fn new(data: &'a [u8]) -> NibbleSlice<'a> {
+        NibbleSlice {
+            data,
+            start: 0,
+            end: data.len() * 2,
+        }
+    }
+
+    /// Path of a hex-prefix encoded key and whether it is a leaf key, see
+    /// `hex_prefix::decode`
+    pub fn from_encoded(data: &'a [u8]) -> Result<(NibbleSlice<'a>, bool), DecodeError> {
+        hex_prefix::decode(data)
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn at(&self, index: usize) -> u8 {
+        assert!(index < self.len(), "nibble index out of range");
+        let position = self.start + index;
+        let byte = self.data[position / 2];
+
+        if position & 1 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        }
+    }
+
+    /// Nibbles from `index` on
+    pub fn mid(&self, index: usize) -> NibbleSlice<'a> {
+        self.split(index).1
+    }
+
+    /// Nibbles in front of `index` and from `index` on
+    pub fn split(&self, index: usize) -> (NibbleSlice<'a>, NibbleSlice<'a>) {
+        assert!(index <= self.len(), "nibble index out of range");
+        let middle = self.start + index;
+
+        (NibbleSlice {data: self.data, start: self.start, end: middle},
+            NibbleSlice {data: self.data, start: middle, end: self.end})
+    }
+
+    /// Length of the longest common prefix
+    pub fn common_prefix(&self, other: &NibbleSlice) -> usize {
+        let mut len = 0;
+
+        while len < self.len() && len < other.len() && self.at(len) == other.at(len) {
+            len += 1;
+        }
+        len
+    }
+
+    pub fn starts_with(&self, prefix: &NibbleSlice) -> bool {
+        self.common_prefix(prefix) == prefix.len()
+    }
+
+    pub fn iter(&self) -> NibbleIter<'a> {
+        NibbleIter {
+            slice: *self,
+            index: 0,
+        }
+    }
+
+    /// Hex-prefix encoding of the slice, see `hex_prefix::encode`
+    pub fn encoded(&self, is_leaf: bool) -> Vec<u8> {
+        hex_prefix::encode(self, is_leaf)
+    }
+}
+
+impl<'a> PartialEq for NibbleSlice<'a> {
+    fn eq(&self, other: &NibbleSlice) -> bool {
+        self.len() == other.len() && self.starts_with(other)
+    }
+}
+
+impl<'a> Eq for NibbleSlice<'a> {}
+
+impl<'a> fmt::Debug for NibbleSlice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for nibble in self.iter() {
+            write!(f, "{:x}", nibble)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct NibbleIter<'a> {
+    slice: NibbleSlice<'a>,
+    index: usize,
+}
+
+impl<'a> Iterator for NibbleIter<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.index == self.slice.len() {
+            return None
+        }
+        self.index += 1;
+        Some(self.slice.at(self.index - 1))
+    }
+}
+
+/// Owned nibbles, packed two to a byte
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct NibbleVec {
+    data: Vec<u8>,
+    len: usize,
+}
+
+impl NibbleVec {
+    pub fn new() -> NibbleVec {
+        NibbleVec::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn at(&self, index: usize) -> u8 {
+        self.as_slice().at(index)
+    }
+
+    pub fn push(&mut self, nibble: u8) {
+        if self.len & 1 == 0 {
+            self.data.push(nibble << 4);
+        } else {
+            *self.data.last_mut().unwrap() |= nibble & 0x0F;
+        }
+        self.len += 1;
+    }
+
+    pub fn extend(&mut self, nibbles: &NibbleSlice) {
+        for nibble in nibbles.iter() {
+            self.push(nibble);
+        }
+    }
+
+    pub fn as_slice<'a>(&'a self) -> NibbleSlice<'a> {
+        NibbleSlice {
+            data: &self.data[..],
+            start: 0,
+            end: self.len,
+        }
+    }
+}
+
+impl<'a> From<NibbleSlice<'a>> for NibbleVec {
+    fn from(slice: NibbleSlice<'a>) -> NibbleVec {
+        let mut result = NibbleVec::new();
+        result.extend(&slice);
+        result
+    }
+}
+
+impl fmt::Debug for NibbleVec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nibbles(values: &[u8]) -> NibbleVec {
+        let mut result = NibbleVec::new();
+        for &value in values {
+            result.push(value);
+        }
+        result
+    }
+
+    #[test]
+    fn slice_test() {
+        let data = [0x12, 0x34, 0x56];
+        let slice = NibbleSlice::new(&data);
+
+        assert_eq!(slice.len(), 6);
+        assert_eq!(slice.iter().collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(slice.mid(3).iter().collect::<Vec<_>>(), vec![4, 5, 6]);
+
+        let (head, tail) = slice.mid(1).split(2);
+        assert_eq!(head, nibbles(&[2, 3]).as_slice());
+        assert_eq!(tail, nibbles(&[4, 5, 6]).as_slice());
+        assert!(slice.mid(6).is_empty());
+
+        assert_eq!(slice.common_prefix(&nibbles(&[1, 2, 3, 7]).as_slice()), 3);
+        assert!(slice.starts_with(&head.split(0).0));
+        assert!(slice.mid(1).starts_with(&head));
+        assert!(!slice.starts_with(&head));
+        assert_eq!(format!("{:?}", slice.mid(1)), "23456");
+    }
+
+    #[test]
+    fn vec_test() {
+        let mut vec = nibbles(&[1, 2, 3]);
+        vec.extend(&NibbleSlice::new(&[0xab]));
+
+        assert_eq!(vec.len(), 5);
+        assert_eq!(vec.at(4), 0x0b);
+        assert_eq!(vec, nibbles(&[1, 2, 3, 0xa, 0xb]));
+        assert_eq!(NibbleVec::from(NibbleSlice::new(&[0x12]).mid(1)), nibbles(&[2]));
+        assert!(NibbleVec::new().is_empty());
+    }
+
+    #[test]
+    fn hex_prefix_test() {
+        // the vectors are in hex_prefix, the wrappers only delegate to it
+        let leaf = nibbles(&[0xf, 1, 0xc, 0xb, 8]);
+        assert_eq!(leaf.as_slice().encoded(true), hex_prefix::encode(&leaf.as_slice(), true));
+        assert_eq!(NibbleSlice::from_encoded(&[0x3f, 0x1c, 0xb8]), Ok((leaf.as_slice(), true)));
+        assert_eq!(NibbleSlice::from_encoded(&[]), Err(DecodeError::EmptyKey));
+    }
+}
@@ -1,20 +1,45 @@
 use ethereum_types::H256;
 use rlp::{Encodable, Decodable, RlpStream, UntrustedRlp, DecoderError};
+use tiny_keccak::keccak256;
+use error::DecodeError;
+use nibble::NibbleVec;
+use hex_prefix;
 use std::clone::Clone;
 use std::str::FromStr;
 use std::fmt::Debug;
+use std::sync::Arc;
 
+/// Children are shared between trees, see `MerkleTree::clone`
+#[derive(Clone)]
 pub enum Node<T: Decodable> {
-    FullNode {nibles: [Option<Box<Node<T>>>; 17], flags: NodeFlag},
-    ShortNode {key: Vec<u8>, node: Box<Node<T>>, flags: NodeFlag},
+    FullNode {nibles: [Option<Arc<Node<T>>>; 17], flags: NodeFlag},
+    /// Leaf if the child is a `ValueNode`, extension otherwise
+    ShortNode {key: NibbleVec, node: Arc<Node<T>>, flags: NodeFlag},
     HashNode {hash: H256},
     ValueNode {value: T},
     Empty,
 }
 
+#[derive(Clone)]
 pub struct NodeFlag {
-    hash: H256,
-    dirty: bool,
+    pub hash: H256,
+    pub dirty: bool,
+}
+
+impl NodeFlag {
+    pub fn new_dirty() -> NodeFlag {
+        NodeFlag{hash: H256::zero(), dirty: true}
+    }
+}
+
+/// Root hash of a tree without any values: keccak256 of the rlp empty string
+pub const EMPTY_ROOT: H256 = H256([
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21
+]);
+
+pub fn keccak(data: &[u8]) -> H256 {
+    H256::from(keccak256(data))
 }
 
 pub fn decode_node<T: Decodable>(hash: &H256, data: &[u8]) -> Result<Node<T>, &'static str> {
@@ -23,7 +48,7 @@ pub fn decode_node<T: Decodable>(hash: &H256, data: &[u8]) -> Result<Node<T>, &'
     }
     let rlp = UntrustedRlp::new(data);
     // This is full node
-    if rlp.val_at::<Vec<u8>>(16).is_ok() {
+    if let Ok(17) = rlp.item_count() {
         return decode_full(hash, rlp);
     }
     // This is short node
@@ -31,30 +56,39 @@ pub fn decode_node<T: Decodable>(hash: &H256, data: &[u8]) -> Result<Node<T>, &'
 }
 
 pub fn decode_short<T: Decodable>(hash: &H256, rlp: UntrustedRlp) -> Result<Node<T>, &'static str> {
-    let key = compact_decode(rlp.val_at::<Vec<u8>>(0).unwrap());
+    let encoded_key = match rlp.val_at::<Vec<u8>>(0) {
+        Ok(encoded_key) => encoded_key,
+        Err(_) => return Err("Invalid RLP"),
+    };
+    let (key, is_leaf) = match hex_prefix::decode(&encoded_key[..]) {
+        Ok((key, is_leaf)) => (NibbleVec::from(key), is_leaf),
+        Err(_) => return Err("Invalid node key"),
+    };
     let flags = NodeFlag{hash: hash.clone(), dirty: false};
 
-    // Is term node
-    if *key.last().unwrap() == 0x10 {
+    if is_leaf {
+        let value = match rlp.val_at::<T>(1) {
+            Ok(value) => value,
+            Err(_) => return Err("Invalid node value"),
+        };
         return Ok(
             Node::ShortNode {
                 key,
-                node: Box::new(Node::ValueNode {
-                    value: rlp.val_at::<T>(1).unwrap()
-                }),
+                node: Arc::new(Node::ValueNode {value}),
                 flags,
             }
         )
     }
-    // This is hash node
-    let data = rlp.val_at::<Vec<u8>>(1).unwrap();
-    let rlp = UntrustedRlp::new(&data[..]);
-    let node : Node<T> = decode_ref(rlp)?;
+    // Child is a hash reference or an inlined node
+    let node : Node<T> = match rlp.at(1) {
+        Ok(child) => decode_ref(child)?,
+        Err(_) => return Err("Invalid RLP"),
+    };
 
     return Ok(
         Node::ShortNode {
             key,
-            node: Box::new(node),
+            node: Arc::new(node),
             flags,
         }
     )
@@ -66,158 +100,269 @@ pub fn decode_full<T: Decodable>(hash: &H256, rlp: UntrustedRlp) -> Result<Node<
     None, None, None, None, None, None, None, None, None, None, None, None, None], flags};
     //
     for index in 0..16 {
-        let data = rlp.val_at::<Vec<u8>>(index).unwrap();
-
-        if let Ok(node_ref) = decode_ref::<T>(UntrustedRlp::new(&data[..])) {
-
+        let child = match rlp.at(index) {
+            Ok(child) => child,
+            Err(_) => return Err("Invalid RLP"),
+        };
+        if let Ok(node_ref) = decode_ref::<T>(child) {
+            if let Node::Empty = node_ref {
+                continue;
+            }
             if let &mut Node::FullNode {ref mut nibles, ref flags} = &mut node {
-                nibles[index] = Some(Box::new(node_ref));
+                nibles[index] = Some(Arc::new(node_ref));
             }
         }
     }
+    match rlp.at(16) {
+        Ok(ref value) if value.is_empty() => return Ok(node),
+        Ok(_) => {},
+        Err(_) => return Err("Invalid RLP"),
+    }
     if let Ok(value) = rlp.val_at::<T>(16) {
         if let &mut Node::FullNode {ref mut nibles, ref flags} = &mut node {
-            nibles[16] = Some(Box::new(Node::ValueNode{value}));
+            nibles[16] = Some(Arc::new(Node::ValueNode{value}));
         }
     }
     Ok(node)
 }
 
 pub fn decode_ref<T: Decodable>(rlp: UntrustedRlp) -> Result<Node<T>, &'static str> {
-    if let Ok(info) = rlp.payload_info() {
+    // Node shorter than 32 bytes embedded into its parent
+    if rlp.is_list() {
         return decode_node(&H256::zero(), rlp.as_raw())
-    } 
-    else if rlp.as_raw().len() == 32 {
+    }
+    let data = match rlp.data() {
+        Ok(data) => data,
+        Err(_) => return Err("Invalid RLP"),
+    };
+    // hash bytes can look like a valid rlp header, so check the length first
+    if data.len() == 32 {
         return Ok(
             Node::HashNode{
-                hash: H256::from_slice(rlp.as_raw())
+                hash: H256::from_slice(data)
             }
         )
     }
-    else if rlp.as_raw().len() == 0 {
+    else if data.is_empty() {
         return Ok(
             Node::Empty
             )
     }
+    else if UntrustedRlp::new(data).payload_info().is_ok() {
+        return decode_node(&H256::zero(), data)
+    }
     return Err("Invalid RLP")
 }
 
-fn compact_encode(mut hex_array : Vec<u8>) -> Vec<u8> {
-    let term = if *hex_array.last().unwrap() == 0x10 {1} else {0};
+/// Decodes a node from an untrusted source, e.g. a proof sent by a peer.
+/// Unlike `decode_node` it never panics and accepts only the canonical
+/// encoding.
+pub fn decode_node_strict<T: Decodable>(hash: &H256, data: &[u8]) -> Result<Node<T>, DecodeError> {
+    let rlp = UntrustedRlp::new(data);
 
-    if term == 1 {
-        hex_array.pop();
+    if rlp.payload_info()?.total() != data.len() {
+        return Err(DecodeError::TrailingBytes)
     }
-    let odd_len = hex_array.len() % 2;
-    let flags = 2 * term + odd_len as u8;
-
-    let hex_array = if odd_len == 1 {
-        let mut array = Vec::new();
-        array.push(flags);
-        array.append(&mut hex_array);
-        array
-    } else {
-        let mut array = Vec::new();
-        array.push(flags);
-        array.push(0);
-        array.append(&mut hex_array);
-        array
-    };
-    let mut result = Vec::with_capacity(hex_array.len()/2);
+    decode_list_strict(hash, &rlp)
+}
+
+fn decode_list_strict<T: Decodable>(hash: &H256, rlp: &UntrustedRlp) -> Result<Node<T>, DecodeError> {
+    let items = list_items(rlp)?;
+    let flags = NodeFlag{hash: *hash, dirty: false};
+
+    match items.len() {
+        2 => {
+            if !items[0].is_data() {
+                return Err(DecodeError::Rlp(DecoderError::RlpExpectedToBeData))
+            }
+            let (key, is_leaf) = hex_prefix::decode(items[0].data()?)?;
+            let key = NibbleVec::from(key);
 
-    for iter in (0..hex_array.len()).step_by(2) {
-        result.push(16*hex_array[iter] + hex_array[iter + 1]);
+            if is_leaf {
+                if !items[1].is_data() {
+                    return Err(DecodeError::Rlp(DecoderError::RlpExpectedToBeData))
+                }
+                let value = items[1].as_val::<T>()?;
+                return Ok(Node::ShortNode {key, node: Arc::new(Node::ValueNode {value}), flags})
+            }
+            if key.is_empty() {
+                return Err(DecodeError::EmptyKey)
+            }
+            match decode_ref_strict(&items[1])? {
+                Node::Empty => Err(DecodeError::ChildSize(0)),
+                node => Ok(Node::ShortNode {key, node: Arc::new(node), flags}),
+            }
+        },
+        17 => {
+            let mut nibles = [None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None];
+
+            for index in 0..16 {
+                match decode_ref_strict(&items[index])? {
+                    Node::Empty => {},
+                    node => nibles[index] = Some(Arc::new(node)),
+                }
+            }
+            if !items[16].is_data() {
+                return Err(DecodeError::Rlp(DecoderError::RlpExpectedToBeData))
+            }
+            if !items[16].is_empty() {
+                nibles[16] = Some(Arc::new(Node::ValueNode {value: items[16].as_val::<T>()?}));
+            }
+            Ok(Node::FullNode {nibles, flags})
+        },
+        count => Err(DecodeError::ItemCount(count)),
     }
-    result
 }
 
-fn compact_decode(hex_array : Vec<u8>) -> Vec<u8> {
-    let odd_len = (hex_array[0] & 0x10) == 0x10;
-    let term = (hex_array[0] & 0x20) == 0x20;
-    //allocate vector with accurate capacity value
-    let mut result = Vec::with_capacity((hex_array.len()- !odd_len as usize)*2 - odd_len as usize + term as usize);
+// Items of a list, which they have to cover exactly
+fn list_items<'a>(rlp: &UntrustedRlp<'a>) -> Result<Vec<UntrustedRlp<'a>>, DecodeError> {
+    if !rlp.is_list() {
+        return Err(DecodeError::Rlp(DecoderError::RlpExpectedToBeList))
+    }
+    let payload = rlp.payload_info()?;
+    let mut items = Vec::new();
+    let mut size = 0;
 
-    if odd_len {
-        result.push(hex_array[0] & 0x0F);
+    while size < payload.value_len {
+        let item = rlp.at(items.len())?;
+        size += item.as_raw().len();
+        items.push(item);
     }
+    if size != payload.value_len {
+        return Err(DecodeError::Rlp(DecoderError::RlpInconsistentLengthAndData))
+    }
+    Ok(items)
+}
 
-    for iter in 1..hex_array.len() {
-        result.push((hex_array[iter] & 0xF0) >> 4);
-        result.push(hex_array[iter] & 0x0F);
+fn decode_ref_strict<T: Decodable>(rlp: &UntrustedRlp) -> Result<Node<T>, DecodeError> {
+    if rlp.is_list() {
+        let size = rlp.as_raw().len();
+        if size >= 32 {
+            return Err(DecodeError::ChildSize(size))
+        }
+        return decode_list_strict(&H256::zero(), rlp)
     }
-    
-    if term {
-        result.push(0x10);
+    let data = rlp.data()?;
+    match data.len() {
+        0 => Ok(Node::Empty),
+        32 => Ok(Node::HashNode {hash: H256::from_slice(data)}),
+        size => Err(DecodeError::ChildSize(size)),
     }
+}
+
+/// Hashes of the stored nodes referenced from an encoded node, including the
+/// ones referenced from inlined children. Values are never decoded, so this
+/// works for any value type.
+pub fn child_hashes(data: &[u8]) -> Vec<H256> {
+    let mut result = Vec::new();
+    collect_child_hashes(UntrustedRlp::new(data), &mut result);
     result
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate rlp;
-    use super::*;
+fn collect_child_hashes(rlp: UntrustedRlp, result: &mut Vec<H256>) {
+    match rlp.item_count() {
+        Ok(17) => {
+            for index in 0..16 {
+                if let Ok(item) = rlp.at(index) {
+                    collect_ref_hashes(item, result);
+                }
+            }
+        },
+        Ok(2) => {
+            // leaf flag in the first nibble of the key, leaf values hold no references
+            let is_leaf = match rlp.at(0).and_then(|key| key.data().map(|key| key.first().cloned())) {
+                Ok(Some(flags)) => flags & 0x20 == 0x20,
+                _ => true,
+            };
+            if !is_leaf {
+                if let Ok(item) = rlp.at(1) {
+                    collect_ref_hashes(item, result);
+                }
+            }
+        },
+        _ => {}
+    }
+}
 
-    #[test]
-    fn compact_encode_test() {
-        // [ 0x01, 0x02, 0x03, 0x04, 0x05 ] -> [ 0x11, 0x23, 0x45 ]
-        let data = vec![0x01, 0x02, 0x03, 0x04, 0x05];
-        let result = compact_encode(data);
-        assert_eq!(result.len(), 3);
-        assert_eq!(result, vec![0x11, 0x23, 0x45]);
-        // [ 0x00, 0x01, 0x02, 0x03, 0x04, 0x05 ] -> [ 0x00, 0x01, 0x23, 0x45 ]
-        let data = vec![0x00, 0x01, 0x02, 0x03, 0x04, 0x05];
-        let result = compact_encode(data);
-        assert_eq!(result.len(), 4);
-        assert_eq!(result, vec![0x00, 0x01, 0x23, 0x45]);
-        // [ 0x00, 0x0f, 0x01, 0x0c, 0x0b, 0x08, 0x10 ] -> [ 0x20, 0x0f, 0x1c, 0xb8 ]
-        let data = vec![0x00, 0x0f, 0x01, 0x0c, 0x0b, 0x08, 0x10];
-        let result = compact_encode(data);
-        assert_eq!(result.len(), 4);
-        assert_eq!(result, vec![0x20, 0x0f, 0x1c, 0xb8]);
-        // [ 0x0f, 0x01, 0x0c, 0x0b, 0x08, 0x10 ] -> [ 0x3f, 0x1c, 0xb8 ]
-        let data = vec![0x0f, 0x01, 0x0c, 0x0b, 0x08, 0x10];
-        let result = compact_encode(data);
-        assert_eq!(result.len(), 3);
-        assert_eq!(result, vec![0x3f, 0x1c, 0xb8]);
+fn collect_ref_hashes(rlp: UntrustedRlp, result: &mut Vec<H256>) {
+    if rlp.is_list() {
+        return collect_child_hashes(rlp, result)
+    }
+    if let Ok(data) = rlp.data() {
+        if data.len() == 32 {
+            result.push(H256::from_slice(data));
+        }
+        else if !data.is_empty() {
+            collect_child_hashes(UntrustedRlp::new(data), result);
+        }
     }
+}
 
-    #[test]
-    fn compact_decode_test() {
-        // [ 0x11, 0x23, 0x45 ] -> [ 0x01, 0x02, 0x03, 0x04, 0x05 ]
-        let data = vec![0x11, 0x23, 0x45];
-        let result = compact_decode(data);
-        assert_eq!(result.len(), 5);
-        assert_eq!(result, vec![0x01, 0x02, 0x03, 0x04, 0x05]);
-        // [ 0x00, 0x01, 0x23, 0x45 ] -> [ 0x00, 0x01, 0x02, 0x03, 0x04, 0x05 ]
-        let data = vec![0x00, 0x01, 0x23, 0x45];
-        let result = compact_decode(data);
-        assert_eq!(result.len(), 6);
-        assert_eq!(result, vec![0x00, 0x01, 0x02, 0x03, 0x04, 0x05]);
-        // [ 0x20, 0x0f, 0x1c, 0xb8 ] -> [ 0x00, 0x0f, 0x01, 0x0c, 0x0b, 0x08, 0x10 ]
-        let data = vec![0x20, 0x0f, 0x1c, 0xb8];
-        let result = compact_decode(data);
-        assert_eq!(result.len(), 7);
-        assert_eq!(result, vec![0x00, 0x0f, 0x01, 0x0c, 0x0b, 0x08, 0x10]);
-        // [ 0x3f, 0x1c, 0xb8 ] -> [ 0x0f, 0x01, 0x0c, 0x0b, 0x08, 0x10 ]
-        let data = vec![0x3f, 0x1c, 0xb8];
-        let result = compact_decode(data);
-        assert_eq!(result.len(), 6);
-        assert_eq!(result, vec![0x0f, 0x01, 0x0c, 0x0b, 0x08, 0x10]);
+/// Leaf values in an encoded node, including the ones in inlined children,
+/// as encoded RLP items. Like `child_hashes` it works for any value type.
+pub fn leaf_values(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut result = Vec::new();
+    collect_leaf_values(UntrustedRlp::new(data), &mut result);
+    result
+}
+
+fn collect_leaf_values(rlp: UntrustedRlp, result: &mut Vec<Vec<u8>>) {
+    match rlp.item_count() {
+        Ok(17) => {
+            for index in 0..16 {
+                if let Ok(item) = rlp.at(index) {
+                    collect_ref_values(item, result);
+                }
+            }
+            if let Ok(item) = rlp.at(16) {
+                if !item.is_empty() {
+                    result.push(item.as_raw().to_vec());
+                }
+            }
+        },
+        Ok(2) => {
+            let is_leaf = match rlp.at(0).and_then(|key| key.data().map(|key| key.first().cloned())) {
+                Ok(Some(flags)) => flags & 0x20 == 0x20,
+                _ => true,
+            };
+            if let Ok(item) = rlp.at(1) {
+                if is_leaf {
+                    result.push(item.as_raw().to_vec());
+                } else {
+                    collect_ref_values(item, result);
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+// Only inlined children hold values, hashed ones are other nodes
+fn collect_ref_values(rlp: UntrustedRlp, result: &mut Vec<Vec<u8>>) {
+    if rlp.is_list() {
+        collect_leaf_values(rlp, result);
     }
+}
 
-    fn check_value_node<T: Decodable + PartialEq + Debug>(node_val: Box<Node<T>>, test_value: T) {
+#[cfg(test)]
+mod tests {
+    extern crate rlp;
+    use super::*;
+
+    fn check_value_node<T: Decodable + PartialEq + Debug>(node_val: Arc<Node<T>>, test_value: T) {
         match *node_val {
-            Node::ValueNode{value} => {
-                assert_eq!(test_value, value)
+            Node::ValueNode{ref value} => {
+                assert_eq!(test_value, *value)
             },
-            Node::ShortNode{node, .. } => {
-                check_value_node::<T>(node, test_value)
+            Node::ShortNode{ref node, .. } => {
+                check_value_node::<T>(node.clone(), test_value)
             },
             _ => {assert!(false)}
         }
     }
 
-    fn check_hash_node<T: Decodable>(node: Box<Node<T>>, test_hash: &H256) {
+    fn check_hash_node<T: Decodable>(node: Arc<Node<T>>, test_hash: &H256) {
         match *node {
             Node::HashNode{hash} => {
                     assert_eq!(*test_hash, hash)
@@ -242,7 +387,7 @@ mod tests {
         let node = node.unwrap();
 
         match node {
-            Node::ShortNode{key, node, flags} => {
+            Node::ShortNode{node, ..} => {
                 check_value_node(node, test_value);
                 
             },
@@ -266,7 +411,7 @@ mod tests {
         let node = node.unwrap();
 
         match node {
-            Node::ShortNode{key, node, flags} => {
+            Node::ShortNode{node, ..} => {
                 check_hash_node::<u64>(node, &test_hash);
                 
             },
@@ -299,4 +444,88 @@ mod tests {
             _ => {assert!(false)}
         }
     }
+
+    fn strict_leaf(key: Vec<u8>, value: u64) -> Vec<u8> {
+        let mut rlp_s = RlpStream::new_list(2);
+        rlp_s.append(&key).append(&value);
+        rlp_s.out()
+    }
+
+    #[test]
+    fn strict_decode_test() {
+        let hash = H256::from(1u64);
+        let leaf = strict_leaf(vec![0x20, 0x0f, 0x1c, 0xb8], 77);
+        match decode_node_strict::<u64>(&hash, &leaf[..]) {
+            Ok(Node::ShortNode{node, ..}) => check_value_node(node, 77),
+            _ => panic!("leaf not decoded"),
+        }
+        // full node with a hashed child, an inlined leaf and a value
+        let mut rlp_s = RlpStream::new_list(17);
+        rlp_s.append(&hash);
+        rlp_s.append_raw(&strict_leaf(vec![0x3f], 5)[..], 1);
+        for _ in 2..16 {
+            rlp_s.append_empty_data();
+        }
+        rlp_s.append(&7u64);
+        let full = rlp_s.out();
+
+        match decode_node_strict::<u64>(&hash, &full[..]) {
+            Ok(Node::FullNode{nibles, ..}) => {
+                check_hash_node(nibles[0].clone().unwrap(), &hash);
+                check_value_node(nibles[1].clone().unwrap(), 5);
+                assert!(nibles[2].is_none());
+                check_value_node(nibles[16].clone().unwrap(), 7);
+            },
+            _ => panic!("full node not decoded"),
+        }
+        // every truncation is rejected without panicking
+        for end in 0..full.len() {
+            assert!(decode_node_strict::<u64>(&hash, &full[..end]).is_err());
+        }
+    }
+
+    #[test]
+    fn strict_reject_test() {
+        fn reject(data: Vec<u8>) -> DecodeError {
+            match decode_node_strict::<u64>(&H256::zero(), &data[..]) {
+                Err(error) => error,
+                Ok(_) => panic!("accepted {:?}", data),
+            }
+        }
+        let mut rlp_s = RlpStream::new_list(3);
+        rlp_s.append(&1u64).append(&2u64).append(&3u64);
+        assert_eq!(reject(rlp_s.out()), DecodeError::ItemCount(3));
+
+        assert_eq!(reject(strict_leaf(vec![0x4f, 0x1c], 1)), DecodeError::InvalidFlags(4));
+        assert_eq!(reject(strict_leaf(vec![0x25, 0x1c], 1)), DecodeError::NonZeroPadding);
+        assert_eq!(reject(strict_leaf(vec![], 1)), DecodeError::EmptyKey);
+
+        let mut trailing = strict_leaf(vec![0x20, 0x1c], 1);
+        trailing.push(0);
+        assert_eq!(reject(trailing), DecodeError::TrailingBytes);
+        assert_eq!(reject(vec![0x82, 0x01, 0x02]), DecodeError::Rlp(DecoderError::RlpExpectedToBeList));
+
+        // extension without nibbles
+        let mut rlp_s = RlpStream::new_list(2);
+        rlp_s.append(&vec![0x00u8]).append(&H256::zero());
+        assert_eq!(reject(rlp_s.out()), DecodeError::EmptyKey);
+
+        // child references that are neither hashes nor short inlined nodes
+        let mut rlp_s = RlpStream::new_list(2);
+        rlp_s.append(&vec![0x11u8]).append(&vec![0u8; 31]);
+        assert_eq!(reject(rlp_s.out()), DecodeError::ChildSize(31));
+
+        let long_leaf = strict_leaf(vec![0x20; 30], 1);
+        let mut rlp_s = RlpStream::new_list(2);
+        rlp_s.append(&vec![0x11u8]).append_raw(&long_leaf[..], 1);
+        assert_eq!(reject(rlp_s.out()), DecodeError::ChildSize(long_leaf.len()));
+
+        // the byte string wrapped inline child decode_node still accepts
+        let short_leaf = strict_leaf(vec![0x3f], 5);
+        let mut rlp_s = RlpStream::new_list(2);
+        rlp_s.append(&vec![0x11u8]).append(&short_leaf);
+        let wrapped = rlp_s.out();
+        assert!(decode_node::<u64>(&H256::zero(), &wrapped[..]).is_ok());
+        assert_eq!(reject(wrapped), DecodeError::ChildSize(short_leaf.len()));
+    }
 }
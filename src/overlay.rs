@@ -0,0 +1,221 @@
+use ethereum_types::H256;
+use rlp::{Encodable, Decodable};
+use error::TrieError;
+use tree::MerkleTree;
+use std::collections::HashMap;
+
+/// Handle of a savepoint taken with `Overlay::savepoint`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint {
+    depth: usize,
+    id: usize,
+}
+
+struct Layer<T> {
+    id: usize,
+    changes: HashMap<H256, Option<T>>,
+}
+
+/// Buffers changes to a tree with nested savepoints, e.g. for EVM call
+/// frames. Writes stay in the overlay until `commit` applies them to the
+/// tree and commits it, so changes that are rolled back never reach the
+/// tree or the database.
+pub struct Overlay<'a, T: 'a + Encodable + Decodable + Clone> {
+    tree: &'a mut MerkleTree<T>,
+    // changes made before the first savepoint come first, then one layer
+    // for every savepoint that is still open
+    layers: Vec<Layer<T>>,
+    next_id: usize,
+}
+
+impl<'a, T: Encodable + Decodable + Clone> Overlay<'a, T> {
+    pub fn new(tree: &'a mut MerkleTree<T>) -> Overlay<'a, T> {
+        Overlay {
+            tree,
+            layers: vec![Layer {id: 0, changes: HashMap::new()}],
+            next_id: 1,
+        }
+    }
+
+    pub fn get(&self, key: &H256) -> Result<Option<T>, TrieError> {
+        for layer in self.layers.iter().rev() {
+            if let Some(value) = layer.changes.get(key) {
+                return Ok(value.clone())
+            }
+        }
+        self.tree.get(key)
+    }
+
+    /// Sets the value of `key`, `None` removes the key
+    pub fn update(&mut self, key: &H256, value: Option<T>) {
+        self.layers.last_mut().unwrap().changes.insert(*key, value);
+    }
+
+    /// Starts a nested scope of changes
+    pub fn savepoint(&mut self) -> Savepoint {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.layers.push(Layer {id, changes: HashMap::new()});
+        Savepoint {
+            depth: self.layers.len() - 1,
+            id,
+        }
+    }
+
+    /// Drops every change made since `savepoint` was taken. The savepoint
+    /// and all savepoints taken after it are closed.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        self.check(savepoint);
+        self.layers.truncate(savepoint.depth);
+    }
+
+    /// Keeps the changes made since `savepoint` as part of the enclosing
+    /// scope. The savepoint and all savepoints taken after it are closed.
+    pub fn release(&mut self, savepoint: Savepoint) {
+        self.check(savepoint);
+
+        while self.layers.len() > savepoint.depth {
+            let layer = self.layers.pop().unwrap();
+            self.layers.last_mut().unwrap().changes.extend(layer.changes);
+        }
+    }
+
+    /// Applies all changes, including those of open savepoints, to the tree
+    /// and commits it. Returns the new root hash. On error the tree is left
+    /// as it was, none of the changes are applied.
+    pub fn commit(mut self) -> Result<H256, TrieError> {
+        let mut changes = HashMap::new();
+
+        for layer in self.layers.drain(..) {
+            changes.extend(layer.changes);
+        }
+        self.tree.update_batch(changes)?;
+        Ok(self.tree.commit())
+    }
+
+    fn check(&self, savepoint: Savepoint) {
+        let open = savepoint.depth > 0 && savepoint.depth < self.layers.len()
+            && self.layers[savepoint.depth].id == savepoint.id;
+        assert!(open, "savepoint {:?} is not open", savepoint);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::Database;
+    use tree::EMPTY_ROOT;
+    use node::{keccak, child_hashes};
+
+    #[test]
+    fn nested_savepoints_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        tree.update(&H256::from(1u64), Some(1)).unwrap();
+        let base = tree.commit();
+
+        let root = {
+            let mut overlay = Overlay::new(&mut tree);
+            overlay.update(&H256::from(2u64), Some(2));
+
+            let outer = overlay.savepoint();
+            overlay.update(&H256::from(1u64), None);
+            overlay.update(&H256::from(3u64), Some(3));
+
+            let inner = overlay.savepoint();
+            overlay.update(&H256::from(3u64), Some(30));
+            assert_eq!(overlay.get(&H256::from(3u64)).unwrap(), Some(30));
+            overlay.rollback_to(inner);
+            assert_eq!(overlay.get(&H256::from(3u64)).unwrap(), Some(3));
+            assert_eq!(overlay.get(&H256::from(1u64)).unwrap(), None);
+
+            overlay.rollback_to(outer);
+            assert_eq!(overlay.get(&H256::from(1u64)).unwrap(), Some(1));
+            assert_eq!(overlay.get(&H256::from(3u64)).unwrap(), None);
+
+            let call = overlay.savepoint();
+            overlay.update(&H256::from(4u64), Some(4));
+            overlay.release(call);
+            assert_eq!(overlay.get(&H256::from(4u64)).unwrap(), Some(4));
+            overlay.commit().unwrap()
+        };
+        assert!(root != base);
+        assert_eq!(tree.root_hash(), root);
+
+        let mut expected = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        expected.update(&H256::from(1u64), Some(1)).unwrap();
+        expected.update(&H256::from(2u64), Some(2)).unwrap();
+        expected.update(&H256::from(4u64), Some(4)).unwrap();
+        assert_eq!(expected.commit(), root);
+    }
+
+    #[test]
+    fn rollback_never_touches_tree_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        tree.update(&H256::from(1u64), Some(1)).unwrap();
+        let base = tree.commit();
+        let mut nodes = 0;
+        db.for_each_node(|_, _| nodes += 1);
+
+        {
+            let mut overlay = Overlay::new(&mut tree);
+            let savepoint = overlay.savepoint();
+            for index in 0..100 {
+                overlay.update(&H256::from(index), Some(index + 1));
+            }
+            overlay.rollback_to(savepoint);
+            // dropping the overlay discards what is left
+            overlay.update(&H256::from(5u64), Some(5));
+        }
+        let mut nodes_after = 0;
+        db.for_each_node(|_, _| nodes_after += 1);
+        assert_eq!(nodes_after, nodes);
+        assert_eq!(tree.get(&H256::from(5u64)).unwrap(), None);
+        assert_eq!(tree.commit(), base);
+    }
+
+    #[test]
+    fn failed_commit_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        for index in 0..100 {
+            tree.update(&keccak(&[index as u8]), Some(index)).unwrap();
+        }
+        let base = tree.commit();
+        // prune the last subtree below the root, keys before it update fine
+        let pruned = *child_hashes(&db.get_value(&base).unwrap()[..]).last().unwrap();
+        db.delete_value(&pruned);
+        let mut tree = MerkleTree::<u64>::new(base, db.clone());
+
+        let result = {
+            let mut overlay = Overlay::new(&mut tree);
+            for index in 0..100 {
+                overlay.update(&keccak(&[index as u8]), Some(index + 100));
+            }
+            overlay.commit()
+        };
+        assert_eq!(result, Err(TrieError::MissingNode(pruned)));
+        for index in 0..100 {
+            match tree.get(&keccak(&[index as u8])) {
+                Ok(value) => assert_eq!(value, Some(index)),
+                Err(error) => assert_eq!(error, TrieError::MissingNode(pruned)),
+            }
+        }
+        assert_eq!(tree.commit(), base);
+    }
+
+    #[test]
+    #[should_panic]
+    fn closed_savepoint_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db);
+        let mut overlay = Overlay::new(&mut tree);
+
+        let first = overlay.savepoint();
+        overlay.release(first);
+        // same depth as the released one
+        overlay.savepoint();
+        overlay.rollback_to(first);
+    }
+}
@@ -0,0 +1,444 @@
+use ethereum_types::{Address, H256, U256};
+use rlp::{Decodable, UntrustedRlp};
+use node::*;
+use nibble::NibbleSlice;
+use state::{Account, KECCAK_EMPTY};
+use serde_json;
+use serde_json::Value;
+use error::{TrieError, ProofError};
+use std::collections::HashMap;
+
+/// Value of `key` in the tree at `root` as shown by `proof`, the encoded
+/// nodes from `MerkleTree::get_proof`. `None` if the proof shows the key
+/// isn't set. Nodes are looked up by their hash, so their order doesn't
+/// matter, and decoded with the strict decoder. Fails with `MissingNode`
+/// if the proof lacks a node on the path.
+pub fn verify_proof<T: Decodable + Clone>(root: &H256, key: &H256, proof: &[Vec<u8>]) -> Result<Option<T>, TrieError> {
+    let nodes: HashMap<H256, &Vec<u8>> = proof.iter().map(|data| (keccak(data), data)).collect();
+    let key_path = NibbleSlice::new(key);
+    let mut hash = *root;
+    let mut consumed = 0;
+
+    if hash == EMPTY_ROOT {
+        return Ok(None)
+    }
+    loop {
+        let data = match nodes.get(&hash) {
+            Some(data) => data,
+            None => return Err(TrieError::MissingNode(hash)),
+        };
+        let node = match decode_node_strict::<T>(&hash, &data[..]) {
+            Ok(node) => node,
+            Err(_) => return Err(TrieError::InvalidNode(hash, key_path.iter().take(consumed).collect())),
+        };
+        match walk(&node, key_path.mid(consumed)) {
+            Step::Value(value) => return Ok(value),
+            Step::Next(next, nibbles) => {
+                hash = next;
+                consumed += nibbles;
+            },
+        }
+    }
+}
+
+enum Step<T> {
+    // the path ends in the node
+    Value(Option<T>),
+    // the path continues in the stored node, this many nibbles further
+    Next(H256, usize),
+}
+
+fn walk<T: Decodable + Clone>(node: &Node<T>, key_path: NibbleSlice) -> Step<T> {
+    match *node {
+        Node::FullNode {ref nibles, ..} => {
+            let (index, rest) = if key_path.is_empty() {
+                (16, key_path)
+            } else {
+                (key_path.at(0) as usize, key_path.mid(1))
+            };
+            match nibles[index] {
+                Some(ref child) => match walk(child, rest) {
+                    Step::Next(hash, nibbles) => Step::Next(hash, nibbles + key_path.len() - rest.len()),
+                    value => value,
+                },
+                None => Step::Value(None),
+            }
+        },
+        Node::ShortNode {ref key, ref node, ..} => {
+            if !key_path.starts_with(&key.as_slice()) {
+                return Step::Value(None)
+            }
+            match walk(node, key_path.mid(key.len())) {
+                Step::Next(hash, nibbles) => Step::Next(hash, nibbles + key.len()),
+                value => value,
+            }
+        },
+        Node::HashNode {hash} => Step::Next(hash, 0),
+        Node::ValueNode {ref value} if key_path.is_empty() => Step::Value(Some(value.clone())),
+        Node::ValueNode {..} | Node::Empty => Step::Value(None),
+    }
+}
+
+/// Proof of one storage slot in an `AccountProof`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageProof {
+    pub key: H256,
+    pub value: U256,
+    /// Nodes of the storage trie on the path to the hashed key
+    pub proof: Vec<Vec<u8>>,
+}
+
+/// Account with its storage slots and their Merkle proofs, the result of
+/// the `eth_getProof` RPC call of EIP-1186. See `StateDB::get_proof`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountProof {
+    pub address: Address,
+    pub balance: U256,
+    pub nonce: U256,
+    pub code_hash: H256,
+    pub storage_hash: H256,
+    /// Nodes of the account trie on the path to the hashed address
+    pub account_proof: Vec<Vec<u8>>,
+    pub storage_proof: Vec<StorageProof>,
+}
+
+impl AccountProof {
+    /// The `eth_getProof` result as JSON, with the field names and hex
+    /// encodings of the RPC
+    pub fn to_json(&self) -> String {
+        let storage_proof: Vec<_> = self.storage_proof.iter().map(|storage| json!({
+            "key": to_hex(&storage.key),
+            "value": quantity_hex(&storage.value),
+            "proof": storage.proof.iter().map(|node| to_hex(node)).collect::<Vec<_>>(),
+        })).collect();
+
+        json!({
+            "address": to_hex(&self.address),
+            "accountProof": self.account_proof.iter().map(|node| to_hex(node)).collect::<Vec<_>>(),
+            "balance": quantity_hex(&self.balance),
+            "codeHash": to_hex(&self.code_hash),
+            "nonce": quantity_hex(&self.nonce),
+            "storageHash": to_hex(&self.storage_hash),
+            "storageProof": storage_proof,
+        }).to_string()
+    }
+
+    /// Reads an `eth_getProof` result, either on its own or as the whole
+    /// JSON-RPC response. Nothing is verified yet, see `verify`.
+    pub fn from_json(text: &str) -> Result<AccountProof, ProofError> {
+        let json: Value = serde_json::from_str(text).map_err(|error| ProofError::Json(error.to_string()))?;
+        if let Some(error) = json.get("error") {
+            return Err(ProofError::Json(format!("rpc error {}", error)))
+        }
+        let json = match json.get("result") {
+            Some(result) => result,
+            None => &json,
+        };
+        let mut storage_proof = Vec::new();
+
+        for storage in array_field(json, "storageProof")? {
+            storage_proof.push(StorageProof {
+                key: storage_key(field(storage, "key")?)?,
+                value: quantity(field(storage, "value")?, "value")?,
+                proof: proof_nodes(storage, "proof")?,
+            });
+        }
+        Ok(AccountProof {
+            address: Address::from_slice(&fixed_data(json, "address", 20)?),
+            balance: quantity(field(json, "balance")?, "balance")?,
+            nonce: quantity(field(json, "nonce")?, "nonce")?,
+            code_hash: H256::from_slice(&fixed_data(json, "codeHash", 32)?),
+            storage_hash: H256::from_slice(&fixed_data(json, "storageHash", 32)?),
+            account_proof: proof_nodes(json, "accountProof")?,
+            storage_proof,
+        })
+    }
+
+    /// Checks the account fields against the account proof for `state_root`
+    /// and every storage value against its proof for the storage hash. An
+    /// account the proof shows to be absent must have empty fields; zero
+    /// hashes, as older clients return them, are accepted for it too.
+    pub fn verify(&self, state_root: &H256) -> Result<(), ProofError> {
+        let claimed = Account {
+            nonce: self.nonce,
+            balance: self.balance,
+            storage_root: self.storage_hash,
+            code_hash: self.code_hash,
+        };
+        let storage_root = match verify_proof::<Vec<u8>>(state_root, &keccak(&self.address), &self.account_proof)? {
+            Some(data) => {
+                match UntrustedRlp::new(&data).as_val::<Account>() {
+                    Ok(ref account) if *account == claimed => {},
+                    _ => return Err(ProofError::AccountMismatch),
+                }
+                self.storage_hash
+            },
+            None => {
+                let empty = self.nonce.is_zero() && self.balance.is_zero()
+                    && (self.storage_hash == EMPTY_ROOT || self.storage_hash.is_zero())
+                    && (self.code_hash == KECCAK_EMPTY || self.code_hash.is_zero());
+                if !empty {
+                    return Err(ProofError::AccountMismatch)
+                }
+                EMPTY_ROOT
+            },
+        };
+
+        for storage in &self.storage_proof {
+            let value = match verify_proof::<Vec<u8>>(&storage_root, &keccak(&storage.key), &storage.proof)? {
+                Some(data) => match UntrustedRlp::new(&data).as_val::<U256>() {
+                    Ok(value) => value,
+                    Err(_) => return Err(ProofError::StorageMismatch(storage.key)),
+                },
+                None => U256::zero(),
+            };
+            if value != storage.value {
+                return Err(ProofError::StorageMismatch(storage.key))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads and verifies an `eth_getProof` response against `state_root`
+pub fn verify_json(text: &str, state_root: &H256) -> Result<AccountProof, ProofError> {
+    let proof = AccountProof::from_json(text)?;
+    proof.verify(state_root)?;
+    Ok(proof)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    const DIGITS: &[u8] = b"0123456789abcdef";
+    let mut result = String::with_capacity(2 + data.len() * 2);
+
+    result.push_str("0x");
+    for byte in data {
+        result.push(DIGITS[(byte >> 4) as usize] as char);
+        result.push(DIGITS[(byte & 0xf) as usize] as char);
+    }
+    result
+}
+
+// Quantity: hex without leading zeros, "0x0" for zero
+fn quantity_hex(value: &U256) -> String {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    let hex = to_hex(&bytes);
+    // the last digit stays for zero
+    let start = hex[2..].find(|digit| digit != '0').unwrap_or(63);
+    format!("0x{}", &hex[2 + start..])
+}
+
+fn field<'a>(json: &'a Value, name: &str) -> Result<&'a Value, ProofError> {
+    json.get(name).ok_or_else(|| ProofError::Json(format!("missing {}", name)))
+}
+
+fn array_field<'a>(json: &'a Value, name: &str) -> Result<&'a Vec<Value>, ProofError> {
+    field(json, name)?.as_array().ok_or_else(|| ProofError::Json(format!("{} isn't an array", name)))
+}
+
+// Bytes of a 0x prefixed hex string, an odd number of digits is read as if
+// it had a leading zero
+fn hex_bytes(json: &Value, name: &str) -> Result<Vec<u8>, ProofError> {
+    let invalid = || ProofError::Json(format!("{} isn't a hex string", name));
+    let text = json.as_str().ok_or_else(&invalid)?;
+    if !text.starts_with("0x") && !text.starts_with("0X") {
+        return Err(invalid())
+    }
+    let digits = text[2..].chars()
+        .map(|digit| digit.to_digit(16).map(|digit| digit as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(&invalid)?;
+    let mut result = Vec::with_capacity(digits.len() / 2 + 1);
+
+    if digits.len() % 2 == 1 {
+        result.push(digits[0]);
+    }
+    for pair in digits[digits.len() % 2..].chunks(2) {
+        result.push(pair[0] << 4 | pair[1]);
+    }
+    Ok(result)
+}
+
+fn fixed_data(json: &Value, name: &str, size: usize) -> Result<Vec<u8>, ProofError> {
+    let data = hex_bytes(field(json, name)?, name)?;
+    if data.len() != size {
+        return Err(ProofError::Json(format!("{} isn't {} bytes", name, size)))
+    }
+    Ok(data)
+}
+
+fn quantity(json: &Value, name: &str) -> Result<U256, ProofError> {
+    let data = hex_bytes(json, name)?;
+    if data.len() > 32 {
+        return Err(ProofError::Json(format!("{} is too large", name)))
+    }
+    Ok(U256::from(&data[..]))
+}
+
+// Clients return storage keys as given, as 32 byte data or as a quantity
+fn storage_key(json: &Value) -> Result<H256, ProofError> {
+    let data = hex_bytes(json, "key")?;
+    if data.len() > 32 {
+        return Err(ProofError::Json("key is too large".to_string()))
+    }
+    let mut key = [0u8; 32];
+    key[32 - data.len()..].copy_from_slice(&data);
+    Ok(H256::from(key))
+}
+
+fn proof_nodes(json: &Value, name: &str) -> Result<Vec<Vec<u8>>, ProofError> {
+    array_field(json, name)?.iter().map(|node| hex_bytes(node, name)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::MerkleTree;
+    use state::StateDB;
+    use db::Database;
+
+    #[test]
+    fn verify_proof_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        assert_eq!(tree.get_proof(&H256::from(1)).unwrap(), Vec::<Vec<u8>>::new());
+        assert_eq!(verify_proof::<u64>(&EMPTY_ROOT, &H256::from(1), &[]), Ok(None));
+        for index in 0..200 {
+            tree.update(&H256::from(index * 0x10001), Some(index)).unwrap();
+        }
+        let root = tree.commit();
+        // uncommitted changes aren't proven
+        tree.update(&H256::from(1), Some(1)).unwrap();
+
+        for index in 0..200 {
+            let key = H256::from(index * 0x10001);
+            let proof = tree.get_proof(&key).unwrap();
+            assert_eq!(verify_proof::<u64>(&root, &key, &proof), Ok(Some(index)));
+            // a node short
+            let hash = keccak(&proof[proof.len() - 1]);
+            assert_eq!(verify_proof::<u64>(&root, &key, &proof[..proof.len() - 1]), Err(TrieError::MissingNode(hash)));
+        }
+        let proof = tree.get_proof(&H256::from(1)).unwrap();
+        assert_eq!(verify_proof::<u64>(&root, &H256::from(1), &proof), Ok(None));
+
+        // a node that hashes right but is no valid node
+        let invalid = vec![0xc3, 0x01, 0x02, 0x03];
+        assert_eq!(verify_proof::<u64>(&keccak(&invalid), &H256::from(1), std::slice::from_ref(&invalid)), Err(TrieError::InvalidNode(keccak(&invalid), Vec::new())));
+    }
+
+    fn test_state() -> (StateDB, H256) {
+        let mut state = StateDB::new(Database::in_memory(), EMPTY_ROOT).unwrap();
+
+        for index in 0..100 {
+            let address = Address::from(index);
+            state.set_balance(&address, U256::from(index * 1000)).unwrap();
+            state.set_nonce(&address, U256::from(index)).unwrap();
+        }
+        state.set_code(&Address::from(7), vec![0x60, 0x00]).unwrap();
+        for slot in 0..50 {
+            state.set_storage(&Address::from(7), &H256::from(slot), U256::from(slot + 1)).unwrap();
+        }
+        let root = state.commit().unwrap();
+        (state, root)
+    }
+
+    #[test]
+    fn account_proof_test() {
+        let (state, root) = test_state();
+        let slots = [H256::from(0), H256::from(49), H256::from(50)];
+        let proof = state.get_proof(&Address::from(7), &slots).unwrap();
+
+        assert_eq!(proof.balance, U256::from(7000));
+        assert_eq!(proof.code_hash, keccak(&[0x60, 0x00]));
+        assert_eq!(proof.storage_proof[0].value, U256::from(1));
+        assert_eq!(proof.storage_proof[2].value, U256::zero());
+        let json = proof.to_json();
+        assert!(json.starts_with("{\"address\":\"0x0000000000000000000000000000000000000007\",\"accountProof\":[\"0x"));
+        assert!(json.contains("\"balance\":\"0x1b58\",\"codeHash\":\"0x"));
+        assert!(json.contains("\"nonce\":\"0x7\",\"storageHash\":\"0x"));
+        assert!(json.contains("{\"key\":\"0x0000000000000000000000000000000000000000000000000000000000000031\",\"value\":\"0x32\",\"proof\":[\"0x"));
+        assert_eq!(verify_json(&json, &root), Ok(proof.clone()));
+
+        // as served over JSON-RPC, with a quantity key
+        let response = format!("{{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":{}}}", json.replace("0x0000000000000000000000000000000000000000000000000000000000000031", "0x31"));
+        assert_eq!(verify_json(&response, &root), Ok(proof.clone()));
+
+        let mut wrong = proof.clone();
+        wrong.balance = U256::from(7001);
+        assert_eq!(verify_json(&wrong.to_json(), &root), Err(ProofError::AccountMismatch));
+        let mut wrong = proof.clone();
+        wrong.storage_proof[1].value = U256::from(1);
+        assert_eq!(verify_json(&wrong.to_json(), &root), Err(ProofError::StorageMismatch(H256::from(49))));
+        let mut wrong = proof.clone();
+        wrong.storage_proof[2].value = U256::from(1);
+        assert_eq!(wrong.verify(&root), Err(ProofError::StorageMismatch(H256::from(50))));
+        let mut wrong = proof.clone();
+        wrong.account_proof.pop();
+        match wrong.verify(&root) {
+            Err(ProofError::Trie(TrieError::MissingNode(_))) => {},
+            result => panic!("{:?}", result),
+        }
+        assert!(proof.verify(&keccak(b"another root")).is_err());
+
+        assert!(matches!(verify_json("{\"error\":{\"code\":-32000}}", &root), Err(ProofError::Json(_))));
+        assert!(matches!(verify_json(&json.replace("\"nonce\":\"0x7\"", "\"nonce\":7"), &root), Err(ProofError::Json(_))));
+    }
+
+    #[test]
+    fn absent_account_proof_test() {
+        let (state, root) = test_state();
+        let proof = state.get_proof(&Address::from(1000), &[H256::from(1)]).unwrap();
+
+        assert_eq!(proof.storage_hash, EMPTY_ROOT);
+        assert_eq!(proof.code_hash, KECCAK_EMPTY);
+        assert!(proof.storage_proof[0].proof.is_empty());
+        assert_eq!(verify_json(&proof.to_json(), &root), Ok(proof.clone()));
+
+        let mut zero_hashes = proof.clone();
+        zero_hashes.storage_hash = H256::zero();
+        zero_hashes.code_hash = H256::zero();
+        assert_eq!(zero_hashes.verify(&root), Ok(()));
+        let mut wrong = proof.clone();
+        wrong.balance = U256::from(1);
+        assert_eq!(wrong.verify(&root), Err(ProofError::AccountMismatch));
+    }
+
+    #[test]
+    fn rpc_fixture_test() {
+        // eth_getProof response of a mainnet node, from the ethers-rs test
+        // data. The capture doesn't record its block, so there is no header
+        // to take the state root from: it is pinned to the hash the capture's
+        // root node had when it was added here, so any change to the proof
+        // nodes is caught.
+        let root: H256 = "57e6e864257daf9d96aaca31edd0cfe4e3892f09061e727c57ab56197dd59287".parse().unwrap();
+        let text = include_str!("../testdata/eth_get_proof.json");
+        let proof = AccountProof::from_json(text).unwrap();
+
+        assert_eq!(verify_json(text, &root), Ok(proof.clone()));
+        assert_eq!(proof.nonce, U256::from(1));
+        assert!(proof.code_hash != KECCAK_EMPTY);
+        // slot 0 is unset, its proof ends in the branch without it
+        assert_eq!(proof.storage_proof[0].value, U256::zero());
+        assert_eq!(AccountProof::from_json(&proof.to_json()), Ok(proof.clone()));
+
+        let mut wrong = proof.clone();
+        wrong.nonce = U256::from(2);
+        assert_eq!(wrong.verify(&root), Err(ProofError::AccountMismatch));
+        let mut wrong = proof.clone();
+        wrong.storage_proof[0].value = U256::from(1);
+        assert_eq!(wrong.verify(&root), Err(ProofError::StorageMismatch(H256::zero())));
+        assert!(proof.verify(&keccak(&proof.account_proof[1])).is_err());
+
+        // a tampered node no longer has the hash its parent refers to
+        for &index in [0, 3].iter() {
+            let mut wrong = proof.clone();
+            let hash = keccak(&wrong.account_proof[index]);
+            let last = wrong.account_proof[index].len() - 1;
+            wrong.account_proof[index][last] ^= 1;
+            assert_eq!(wrong.verify(&root), Err(ProofError::Trie(TrieError::MissingNode(hash))));
+            assert_eq!(verify_json(&wrong.to_json(), &root), Err(ProofError::Trie(TrieError::MissingNode(hash))));
+        }
+    }
+}
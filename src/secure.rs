@@ -0,0 +1,165 @@
+use ethereum_types::H256;
+use rlp::{Encodable, Decodable};
+use tree::{MerkleTree, TreeIterator};
+use node::keccak;
+use db::Database;
+use error::TrieError;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Tree keyed by the keccak hash of the key, the way Ethereum stores
+/// accounts and contract storage. Hashed keys are spread evenly, so nobody
+/// can choose keys that make paths long. Keys can be of any length.
+///
+/// If the database records preimages, updated keys are stored by their hash
+/// on `commit`, and `iter_with_preimages` shows them.
+#[derive(Clone)]
+pub struct SecureMerkleTree<T: Encodable + Decodable + Clone> {
+    tree: MerkleTree<T>,
+    db: Arc<Database>,
+    // keys updated since the last commit
+    preimages: HashMap<H256, Vec<u8>>,
+}
+
+// Encoded nodes or keys by their keccak hash
+type HashedValues = Vec<(H256, Vec<u8>)>;
+
+impl<T: Encodable + Decodable + Clone> SecureMerkleTree<T> {
+    /// Same as `MerkleTree::new`
+    pub fn new(hash: H256, db: Arc<Database>) -> SecureMerkleTree<T> {
+        SecureMerkleTree {
+            tree: MerkleTree::new(hash, db.clone()),
+            db,
+            preimages: HashMap::new(),
+        }
+    }
+
+    /// Same as `MerkleTree::at_root`
+    pub fn at_root(db: Arc<Database>, root: H256) -> Result<SecureMerkleTree<T>, TrieError> {
+        Ok(SecureMerkleTree {
+            tree: MerkleTree::at_root(db.clone(), root)?,
+            db,
+            preimages: HashMap::new(),
+        })
+    }
+
+    pub fn root_hash(&self) -> H256 {
+        self.tree.root_hash()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<T>, TrieError> {
+        self.tree.get(&keccak(key))
+    }
+
+    pub fn update(&mut self, key: &[u8], value: Option<T>) -> Result<(), TrieError> {
+        let hash = keccak(key);
+        let record = value.is_some() && self.db.record_preimages();
+
+        self.tree.update(&hash, value)?;
+        if record {
+            self.preimages.insert(hash, key.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Iterates like `MerkleTree::iter` over the hashed keys, each with the
+    /// original key if it is known
+    pub fn iter_with_preimages<'a>(&'a self) -> PreimageIterator<'a, T> {
+        PreimageIterator {
+            tree: self,
+            inner: self.tree.iter(),
+        }
+    }
+
+    /// Same as `MerkleTree::get_proof` for the hashed key
+    pub fn get_proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>, TrieError> {
+        self.tree.get_proof(&keccak(key))
+    }
+
+    pub fn commit(&mut self) -> H256 {
+        if !self.preimages.is_empty() {
+            self.db.insert_preimages(&self.preimages.drain().collect());
+        }
+        self.tree.commit()
+    }
+
+    /// Same as `MerkleTree::hash_changes`, also returns the preimages to
+    /// record
+    pub(crate) fn hash_changes(&mut self) -> (H256, HashedValues, HashedValues) {
+        let (root, nodes) = self.tree.hash_changes();
+        (root, nodes, self.preimages.drain().collect())
+    }
+
+    /// The tree underneath, keyed by the hashed keys
+    pub fn raw(&self) -> &MerkleTree<T> {
+        &self.tree
+    }
+}
+
+/// Iterator over a secure tree with the original keys, see
+/// `SecureMerkleTree::iter_with_preimages`
+pub struct PreimageIterator<'a, T: 'a + Encodable + Decodable + Clone> {
+    tree: &'a SecureMerkleTree<T>,
+    inner: TreeIterator<T>,
+}
+
+impl<'a, T: Encodable + Decodable + Clone> Iterator for PreimageIterator<'a, T> {
+    type Item = Result<(H256, Option<Vec<u8>>, T), TrieError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|item| item.map(|(hash, value)| {
+            let preimage = match self.tree.preimages.get(&hash) {
+                Some(key) => Some(key.clone()),
+                None => self.tree.db.preimage(&hash),
+            };
+            (hash, preimage, value)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node::EMPTY_ROOT;
+
+    #[test]
+    fn secure_tree_test() {
+        let db = Database::in_memory();
+        let mut tree = SecureMerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        tree.update(b"short", Some(1)).unwrap();
+        tree.update(b"a key longer than the 32 bytes of a hash", Some(2)).unwrap();
+        let root = tree.commit();
+
+        let tree = SecureMerkleTree::<u64>::at_root(db.clone(), root).unwrap();
+        assert_eq!(tree.get(b"short").unwrap(), Some(1));
+        assert_eq!(tree.get(b"a key longer than the 32 bytes of a hash").unwrap(), Some(2));
+        assert_eq!(tree.get(b"missing").unwrap(), None);
+        // stored under the hash of the key
+        assert_eq!(tree.raw().get(&keccak(b"short")).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn preimages_test() {
+        let db = Database::in_memory();
+        let mut tree = SecureMerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        tree.update(b"not recorded", Some(1)).unwrap();
+        db.set_record_preimages(true);
+        tree.update(b"committed", Some(2)).unwrap();
+        tree.commit();
+        tree.update(b"uncommitted", Some(3)).unwrap();
+        assert_eq!(db.preimage(&keccak(b"committed")), Some(b"committed".to_vec()));
+        assert_eq!(db.preimage(&keccak(b"uncommitted")), None);
+
+        let mut items: Vec<_> = tree.iter_with_preimages().map(|item| item.unwrap()).collect();
+        items.sort_by_key(|&(_, _, value)| value);
+        assert_eq!(items, vec![
+            (keccak(b"not recorded"), None, 1),
+            (keccak(b"committed"), Some(b"committed".to_vec()), 2),
+            (keccak(b"uncommitted"), Some(b"uncommitted".to_vec()), 3),
+        ]);
+        tree.commit();
+        assert_eq!(db.preimage(&keccak(b"uncommitted")), Some(b"uncommitted".to_vec()));
+    }
+}
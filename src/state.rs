@@ -0,0 +1,501 @@
+use ethereum_types::{Address, H256, U256};
+use rlp;
+use rlp::{Encodable, Decodable, RlpStream, UntrustedRlp, DecoderError};
+use secure::SecureMerkleTree;
+use node::{EMPTY_ROOT, keccak, leaf_values};
+use db::Database;
+use error::TrieError;
+use proof::{AccountProof, StorageProof};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Hash of empty code, the code hash of accounts without code
+pub const KECCAK_EMPTY: H256 = H256([
+    0xc5, 0xd2, 0x46, 0x01, 0x86, 0xf7, 0x23, 0x3c, 0x92, 0x7e, 0x7d, 0xb2, 0xdc, 0xc7, 0x03, 0xc0,
+    0xe5, 0x00, 0xb6, 0x53, 0xca, 0x82, 0x27, 0x3b, 0x7b, 0xfa, 0xd8, 0x04, 0x5d, 0x85, 0xa4, 0x70
+]);
+
+/// Account as stored in the state trie
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub nonce: U256,
+    pub balance: U256,
+    /// Root of the account's storage trie
+    pub storage_root: H256,
+    pub code_hash: H256,
+}
+
+impl Default for Account {
+    fn default() -> Account {
+        Account {
+            nonce: U256::zero(),
+            balance: U256::zero(),
+            storage_root: EMPTY_ROOT,
+            code_hash: KECCAK_EMPTY,
+        }
+    }
+}
+
+impl Account {
+    /// Accounts in the leaves of an encoded account trie node, values that
+    /// aren't accounts are skipped
+    pub fn from_node(data: &[u8]) -> Vec<Account> {
+        leaf_values(data).iter()
+            .filter_map(|value| UntrustedRlp::new(value).as_val::<Vec<u8>>().ok())
+            .filter_map(|value| UntrustedRlp::new(&value).as_val::<Account>().ok())
+            .collect()
+    }
+}
+
+impl Encodable for Account {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(4)
+            .append(&self.nonce)
+            .append(&self.balance)
+            .append(&self.storage_root)
+            .append(&self.code_hash);
+    }
+}
+
+impl Decodable for Account {
+    fn decode(rlp: &UntrustedRlp) -> Result<Account, DecoderError> {
+        if rlp.item_count()? != 4 {
+            return Err(DecoderError::RlpIncorrectListLen)
+        }
+        Ok(Account {
+            nonce: rlp.val_at(0)?,
+            balance: rlp.val_at(1)?,
+            storage_root: rlp.val_at(2)?,
+            code_hash: rlp.val_at(3)?,
+        })
+    }
+}
+
+/// Ethereum world state: a secure trie of accounts by address and a secure
+/// storage trie per account, all in the same database.
+///
+/// Trie values are the RLP encoded accounts and storage values as byte
+/// strings, as in Ethereum, so roots match those of other clients. Storage
+/// changes are kept in the account's storage trie until `commit`, which
+/// commits the storage tries first and then the account trie with the new
+/// storage roots. Code is kept by its hash in the database's code storage.
+pub struct StateDB {
+    db: Arc<Database>,
+    accounts: SecureMerkleTree<Vec<u8>>,
+    // storage tries changed since the last commit
+    storage: HashMap<Address, SecureMerkleTree<Vec<u8>>>,
+    // code set since the last commit by hash
+    code: HashMap<H256, Vec<u8>>,
+}
+
+impl StateDB {
+    /// Opens the state at `root`, `EMPTY_ROOT` for an empty state
+    pub fn new(db: Arc<Database>, root: H256) -> Result<StateDB, TrieError> {
+        Ok(StateDB {
+            accounts: SecureMerkleTree::at_root(db.clone(), root)?,
+            db,
+            storage: HashMap::new(),
+            code: HashMap::new(),
+        })
+    }
+
+    /// Root of the last commit
+    pub fn root(&self) -> H256 {
+        self.accounts.root_hash()
+    }
+
+    pub fn account(&self, address: &Address) -> Result<Option<Account>, TrieError> {
+        match self.accounts.get(address)? {
+            Some(data) => Ok(Some(decode_value(address, &data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Balance of the account, zero if it doesn't exist
+    pub fn balance(&self, address: &Address) -> Result<U256, TrieError> {
+        Ok(self.account(address)?.map_or(U256::zero(), |account| account.balance))
+    }
+
+    /// Sets the balance, the account is created if it doesn't exist
+    pub fn set_balance(&mut self, address: &Address, balance: U256) -> Result<(), TrieError> {
+        let mut account = self.account(address)?.unwrap_or_default();
+        account.balance = balance;
+        self.accounts.update(address, Some(rlp::encode(&account).to_vec()))
+    }
+
+    /// Sets the nonce, the account is created if it doesn't exist
+    pub fn set_nonce(&mut self, address: &Address, nonce: U256) -> Result<(), TrieError> {
+        let mut account = self.account(address)?.unwrap_or_default();
+        account.nonce = nonce;
+        self.accounts.update(address, Some(rlp::encode(&account).to_vec()))
+    }
+
+    /// Code of the account, `None` if the account doesn't exist
+    pub fn get_code(&self, address: &Address) -> Result<Option<Vec<u8>>, TrieError> {
+        let code_hash = match self.account(address)? {
+            Some(account) => account.code_hash,
+            None => return Ok(None),
+        };
+        if code_hash == KECCAK_EMPTY {
+            return Ok(Some(Vec::new()))
+        }
+        if let Some(code) = self.code.get(&code_hash) {
+            return Ok(Some(code.clone()))
+        }
+        match self.db.get_code(&code_hash) {
+            Some(code) => Ok(Some(code)),
+            None => Err(TrieError::MissingCode(code_hash)),
+        }
+    }
+
+    /// Sets the code, the account is created if it doesn't exist. The code is
+    /// written to the database by `commit`.
+    pub fn set_code(&mut self, address: &Address, code: Vec<u8>) -> Result<(), TrieError> {
+        let mut account = self.account(address)?.unwrap_or_default();
+        account.code_hash = keccak(&code);
+        if account.code_hash != KECCAK_EMPTY {
+            self.code.insert(account.code_hash, code);
+        }
+        self.accounts.update(address, Some(rlp::encode(&account).to_vec()))
+    }
+
+    /// Value of a storage slot, zero if it was never set. Uncommitted
+    /// changes are included.
+    pub fn get_storage(&self, address: &Address, slot: &H256) -> Result<U256, TrieError> {
+        let value = match self.storage.get(address) {
+            Some(storage) => storage.get(slot)?,
+            None => self.storage_trie(address)?.get(slot)?,
+        };
+        match value {
+            Some(data) => decode_value(slot, &data),
+            None => Ok(U256::zero()),
+        }
+    }
+
+    /// Sets a storage slot, zero removes it. The storage root of the account
+    /// is updated by `commit`.
+    pub fn set_storage(&mut self, address: &Address, slot: &H256, value: U256) -> Result<(), TrieError> {
+        if !self.storage.contains_key(address) {
+            let storage = self.storage_trie(address)?;
+            self.storage.insert(*address, storage);
+        }
+        let value = if value.is_zero() {
+            None
+        } else {
+            Some(rlp::encode(&value).to_vec())
+        };
+        self.storage.get_mut(address).unwrap().update(slot, value)
+    }
+
+    /// Writes the new code, commits the changed storage tries, stores their
+    /// roots in the accounts and commits the account trie. Returns the new
+    /// state root. Everything is written in one batch once the accounts are
+    /// updated, so on error nothing is written and the changes are kept.
+    pub fn commit(&mut self) -> Result<H256, TrieError> {
+        let mut accounts = self.accounts.clone();
+        let mut nodes = Vec::new();
+        let mut roots = Vec::new();
+        let mut preimages = Vec::new();
+
+        for (address, storage) in self.storage.iter() {
+            let (storage_root, storage_nodes, storage_preimages) = storage.clone().hash_changes();
+            let account = match accounts.get(address)? {
+                Some(data) => decode_value(address, &data)?,
+                // storage that was set and cleared again doesn't create an account
+                None if storage_root == EMPTY_ROOT => continue,
+                None => Account::default(),
+            };
+            let account = Account {storage_root, ..account};
+            accounts.update(address, Some(rlp::encode(&account).to_vec()))?;
+            nodes.extend(storage_nodes);
+            preimages.extend(storage_preimages);
+            if storage_root != EMPTY_ROOT {
+                roots.push(storage_root);
+            }
+        }
+        let (root, account_nodes, account_preimages) = accounts.hash_changes();
+        nodes.extend(account_nodes);
+        preimages.extend(account_preimages);
+        if root != EMPTY_ROOT {
+            roots.push(root);
+        }
+        let code: Vec<_> = self.code.drain().map(|(_, code)| code).collect();
+        self.db.insert_state(&nodes, &roots, &code, &preimages);
+
+        self.accounts = accounts;
+        self.storage.clear();
+        Ok(root)
+    }
+
+    /// EIP-1186 proof of the account and the storage slots at the last
+    /// committed root, uncommitted changes are not included. An account that
+    /// doesn't exist is proven absent and has empty fields.
+    pub fn get_proof(&self, address: &Address, slots: &[H256]) -> Result<AccountProof, TrieError> {
+        let accounts = SecureMerkleTree::<Vec<u8>>::at_root(self.db.clone(), self.root())?;
+        let account = match accounts.get(address)? {
+            Some(data) => decode_value(address, &data)?,
+            None => Account::default(),
+        };
+        let storage = SecureMerkleTree::<Vec<u8>>::at_root(self.db.clone(), account.storage_root)?;
+        let mut storage_proof = Vec::new();
+
+        for slot in slots {
+            let value = match storage.get(slot)? {
+                Some(data) => decode_value(slot, &data)?,
+                None => U256::zero(),
+            };
+            storage_proof.push(StorageProof {
+                key: *slot,
+                value,
+                proof: storage.get_proof(slot)?,
+            });
+        }
+        Ok(AccountProof {
+            address: *address,
+            balance: account.balance,
+            nonce: account.nonce,
+            code_hash: account.code_hash,
+            storage_hash: account.storage_root,
+            account_proof: accounts.get_proof(address)?,
+            storage_proof,
+        })
+    }
+
+    // Storage trie of the account at its last committed storage root
+    fn storage_trie(&self, address: &Address) -> Result<SecureMerkleTree<Vec<u8>>, TrieError> {
+        let storage_root = self.account(address)?.map_or(EMPTY_ROOT, |account| account.storage_root);
+        SecureMerkleTree::at_root(self.db.clone(), storage_root)
+    }
+}
+
+// Account or storage value stored under `key` in a secure trie
+fn decode_value<T: Decodable>(key: &[u8], data: &[u8]) -> Result<T, TrieError> {
+    UntrustedRlp::new(data).as_val().map_err(|_| TrieError::InvalidValue(keccak(key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn account_rlp_test() {
+        assert_eq!(keccak(&[]), KECCAK_EMPTY);
+        let account = Account {
+            nonce: U256::from(1),
+            balance: U256::from(1000),
+            storage_root: EMPTY_ROOT,
+            code_hash: KECCAK_EMPTY,
+        };
+        let encoded = rlp::encode(&account);
+        assert_eq!(rlp::decode::<Account>(&encoded[..]), account);
+        assert!(UntrustedRlp::new(&rlp::encode(&U256::from(1))[..]).as_val::<Account>().is_err());
+    }
+
+    #[test]
+    fn state_test() {
+        let db = Database::in_memory();
+        let mut state = StateDB::new(db.clone(), EMPTY_ROOT).unwrap();
+        let alice = Address::from(1);
+        let bob = Address::from(2);
+
+        state.set_balance(&alice, U256::from(100)).unwrap();
+        state.set_nonce(&alice, U256::from(3)).unwrap();
+        state.set_storage(&bob, &H256::from(1), U256::from(42)).unwrap();
+        state.set_storage(&bob, &H256::from(2), U256::from(43)).unwrap();
+        // uncommitted storage is visible
+        assert_eq!(state.get_storage(&bob, &H256::from(1)).unwrap(), U256::from(42));
+        let root = state.commit().unwrap();
+        assert_eq!(state.root(), root);
+
+        let mut state = StateDB::new(db.clone(), root).unwrap();
+        assert_eq!(state.balance(&alice).unwrap(), U256::from(100));
+        assert_eq!(state.account(&alice).unwrap().unwrap().nonce, U256::from(3));
+        assert_eq!(state.balance(&Address::from(3)).unwrap(), U256::zero());
+        assert_eq!(state.get_storage(&bob, &H256::from(2)).unwrap(), U256::from(43));
+        assert_eq!(state.get_storage(&alice, &H256::from(1)).unwrap(), U256::zero());
+
+        // the storage root is the root of a secure trie of the encoded values
+        let mut storage = SecureMerkleTree::<Vec<u8>>::new(EMPTY_ROOT, db.clone());
+        storage.update(&H256::from(1), Some(rlp::encode(&U256::from(42)).to_vec())).unwrap();
+        storage.update(&H256::from(2), Some(rlp::encode(&U256::from(43)).to_vec())).unwrap();
+        let bob_account = state.account(&bob).unwrap().unwrap();
+        assert_eq!(bob_account.storage_root, storage.commit());
+        assert_eq!(bob_account.balance, U256::zero());
+
+        // clearing every slot empties the storage trie
+        state.set_storage(&bob, &H256::from(1), U256::zero()).unwrap();
+        state.set_storage(&bob, &H256::from(2), U256::zero()).unwrap();
+        state.set_storage(&alice, &H256::from(5), U256::from(5)).unwrap();
+        let new_root = state.commit().unwrap();
+        assert_eq!(state.account(&bob).unwrap().unwrap().storage_root, EMPTY_ROOT);
+        assert_eq!(state.get_storage(&alice, &H256::from(5)).unwrap(), U256::from(5));
+        assert_eq!(state.account(&alice).unwrap().unwrap().balance, U256::from(100));
+
+        // the old state is still there
+        let old = StateDB::new(db.clone(), root).unwrap();
+        assert_eq!(old.get_storage(&bob, &H256::from(1)).unwrap(), U256::from(42));
+        assert!(new_root != root);
+
+        // setting and clearing storage of a new address doesn't create it
+        let carol = Address::from(4);
+        state.set_storage(&carol, &H256::from(1), U256::from(1)).unwrap();
+        state.set_storage(&carol, &H256::from(1), U256::zero()).unwrap();
+        assert_eq!(state.commit().unwrap(), new_root);
+        assert_eq!(state.account(&carol).unwrap(), None);
+    }
+
+    #[test]
+    fn code_test() {
+        let db = Database::in_memory();
+        let mut state = StateDB::new(db.clone(), EMPTY_ROOT).unwrap();
+        let contract = Address::from(1);
+        let code = vec![0x60, 0x00, 0x60, 0x00, 0xf3];
+
+        assert_eq!(state.get_code(&contract).unwrap(), None);
+        state.set_code(&contract, code.clone()).unwrap();
+        assert_eq!(state.get_code(&contract).unwrap(), Some(code.clone()));
+        assert_eq!(db.get_code(&keccak(&code)), None);
+        let root = state.commit().unwrap();
+        assert_eq!(db.get_code(&keccak(&code)), Some(code.clone()));
+
+        let mut state = StateDB::new(db.clone(), root).unwrap();
+        assert_eq!(state.account(&contract).unwrap().unwrap().code_hash, keccak(&code));
+        assert_eq!(state.get_code(&contract).unwrap(), Some(code.clone()));
+        state.set_balance(&Address::from(2), U256::from(1)).unwrap();
+        assert_eq!(state.get_code(&Address::from(2)).unwrap(), Some(Vec::new()));
+
+        db.delete_code(&vec![keccak(&code)]);
+        assert_eq!(state.get_code(&contract), Err(TrieError::MissingCode(keccak(&code))));
+    }
+
+    // Leaf node of a secure trie holding the whole hashed key, encoded by
+    // hand as in the yellow paper
+    fn leaf(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut path = vec![0x20];
+        path.extend_from_slice(&keccak(key)[..]);
+        let mut rlp_s = RlpStream::new_list(2);
+        rlp_s.append(&path).append(&value.to_vec());
+        rlp_s.out()
+    }
+
+    #[test]
+    fn known_root_test() {
+        let db = Database::in_memory();
+        let mut state = StateDB::new(db.clone(), EMPTY_ROOT).unwrap();
+        let alice = Address::from(1);
+        let slot = H256::from(1);
+
+        state.set_balance(&alice, U256::from(1000)).unwrap();
+        state.set_storage(&alice, &slot, U256::from(42)).unwrap();
+        let storage_root = keccak(&leaf(&slot, &[42])[..]);
+        let account = Account {balance: U256::from(1000), storage_root, ..Account::default()};
+        let root = keccak(&leaf(&alice, &rlp::encode(&account))[..]);
+        assert_eq!(state.commit().unwrap(), root);
+
+        // a second account whose hashed key starts with another nibble
+        let bob = (2..).map(Address::from).find(|bob| keccak(bob)[0] >> 4 != keccak(&alice)[0] >> 4).unwrap();
+        state.set_nonce(&bob, U256::from(1)).unwrap();
+        let bob_account = Account {nonce: U256::from(1), ..Account::default()};
+        let mut branch = RlpStream::new_list(17);
+        for index in 0..17 {
+            let (address, account) = if index == keccak(&alice)[0] >> 4 {
+                (alice, &account)
+            } else if index == keccak(&bob)[0] >> 4 {
+                (bob, &bob_account)
+            } else {
+                branch.append_empty_data();
+                continue;
+            };
+            // the leaf keeps the 63 nibbles after the branch
+            let hashed = keccak(&address);
+            let mut path = vec![0x30 | hashed[0] & 0x0F];
+            path.extend_from_slice(&hashed.0[1..]);
+            let mut rlp_s = RlpStream::new_list(2);
+            rlp_s.append(&path).append(&rlp::encode(account).to_vec());
+            branch.append(&keccak(&rlp_s.out()[..]));
+        }
+        assert_eq!(state.commit().unwrap(), keccak(&branch.out()[..]));
+    }
+
+    #[test]
+    fn failed_commit_test() {
+        let db = Database::in_memory();
+        let mut state = StateDB::new(db.clone(), EMPTY_ROOT).unwrap();
+        for index in 0..20 {
+            state.set_balance(&Address::from(index), U256::from(index)).unwrap();
+        }
+        let root = state.commit().unwrap();
+        let alice = Address::from(1);
+        let bob = Address::from(2);
+        let code = vec![0x60, 0x00];
+
+        let mut state = StateDB::new(db.clone(), root).unwrap();
+        state.set_storage(&alice, &H256::from(1), U256::from(42)).unwrap();
+        state.set_storage(&bob, &H256::from(1), U256::from(43)).unwrap();
+        state.set_code(&bob, code.clone()).unwrap();
+        // lose the leaf of alice's account
+        let leaf = keccak(&state.get_proof(&alice, &[]).unwrap().account_proof.pop().unwrap());
+        let data = db.get_value(&leaf).unwrap();
+        db.delete_value(&leaf);
+        let mut stored = Vec::new();
+        db.for_each_node(|hash, _| stored.push((*hash, db.ref_count(hash))));
+
+        assert_eq!(state.commit(), Err(TrieError::MissingNode(leaf)));
+        let mut after = Vec::new();
+        db.for_each_node(|hash, _| after.push((*hash, db.ref_count(hash))));
+        assert_eq!(after, stored);
+        assert_eq!(db.get_code(&keccak(&code)), None);
+        assert_eq!(state.root(), root);
+
+        // the changes are kept for another try
+        db.set_value(&leaf, &data);
+        let new_root = state.commit().unwrap();
+        let state = StateDB::new(db.clone(), new_root).unwrap();
+        assert_eq!(state.get_storage(&alice, &H256::from(1)).unwrap(), U256::from(42));
+        assert_eq!(state.get_storage(&bob, &H256::from(1)).unwrap(), U256::from(43));
+        assert_eq!(state.get_code(&bob).unwrap(), Some(code));
+    }
+
+    #[test]
+    fn invalid_value_test() {
+        let db = Database::in_memory();
+        let alice = Address::from(1);
+        let slot = H256::from(1);
+        let mut accounts = SecureMerkleTree::<Vec<u8>>::new(EMPTY_ROOT, db.clone());
+        accounts.update(&alice, Some(vec![0x01, 0x02])).unwrap();
+        let root = accounts.commit();
+
+        let mut state = StateDB::new(db.clone(), root).unwrap();
+        assert_eq!(state.account(&alice), Err(TrieError::InvalidValue(keccak(&alice))));
+        assert_eq!(state.balance(&alice), Err(TrieError::InvalidValue(keccak(&alice))));
+        assert_eq!(state.get_storage(&alice, &slot), Err(TrieError::InvalidValue(keccak(&alice))));
+        assert!(state.get_proof(&alice, &[slot]).is_err());
+        assert!(state.set_balance(&alice, U256::from(1)).is_err());
+
+        // a storage value that isn't a number
+        let mut storage = SecureMerkleTree::<Vec<u8>>::new(EMPTY_ROOT, db.clone());
+        storage.update(&slot, Some(vec![0xc0])).unwrap();
+        let account = Account {storage_root: storage.commit(), ..Account::default()};
+        accounts.update(&alice, Some(rlp::encode(&account).to_vec())).unwrap();
+        let state = StateDB::new(db.clone(), accounts.commit()).unwrap();
+        assert_eq!(state.get_storage(&alice, &slot), Err(TrieError::InvalidValue(keccak(&slot))));
+        assert_eq!(state.get_proof(&alice, &[slot]).err(), Some(TrieError::InvalidValue(keccak(&slot))));
+    }
+
+    #[test]
+    fn accounts_from_node_test() {
+        let db = Database::in_memory();
+        let mut state = StateDB::new(db.clone(), EMPTY_ROOT).unwrap();
+
+        for index in 0..20 {
+            state.set_balance(&Address::from(index), U256::from(index)).unwrap();
+        }
+        state.commit().unwrap();
+        let mut balances = Vec::new();
+        db.for_each_node(|_, data| {
+            balances.extend(Account::from_node(data).into_iter().map(|account| account.balance.low_u64()));
+        });
+        balances.sort();
+        assert_eq!(balances, (0..20).collect::<Vec<u64>>());
+    }
+}
@@ -0,0 +1,529 @@
+use ethereum_types::H256;
+use rlp::Decodable;
+use node::*;
+use db::*;
+use error::TrieError;
+use state::{Account, KECCAK_EMPTY};
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// Downloads the tree at a root node by node, e.g. from peers.
+///
+/// `missing` reports hashes of nodes that aren't in the database yet, the
+/// caller fetches them from any source and hands them to `process`. Every
+/// node is checked against its hash and decoded with the strict decoder to
+/// find its children before it is stored. Nodes that are already stored are
+/// walked as well, so an interrupted sync can be continued by a new
+/// `TrieSync` at the same root.
+///
+/// Nodes are written as they arrive, the root is readable only once the sync
+/// is complete.
+pub struct TrieSync<T: Decodable> {
+    db: Arc<Database>,
+    root: H256,
+    // hashes to look up in the database with the paths of their nodes
+    queue: Vec<(H256, Vec<u8>)>,
+    // hashes that have to be fetched, in the order they were found
+    missing: Vec<(H256, Vec<u8>)>,
+    // every hash ever queued, nodes are shared between subtrees
+    seen: HashSet<H256>,
+    // whether this sync stored the root node
+    stored_root: bool,
+    complete: bool,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Decodable> TrieSync<T> {
+    pub fn new(db: Arc<Database>, root: H256) -> TrieSync<T> {
+        let mut sync = TrieSync {
+            db,
+            root,
+            queue: Vec::new(),
+            missing: Vec::new(),
+            seen: HashSet::new(),
+            stored_root: false,
+            complete: root == EMPTY_ROOT,
+            _marker: PhantomData,
+        };
+        if !sync.complete {
+            sync.schedule(root, Vec::new());
+        }
+        sync
+    }
+
+    /// Up to `max` hashes of nodes to fetch. A hash is reported again until
+    /// its node is processed. Once nothing is missing the sync is complete
+    /// and the root is referenced like the root of a commit, unless it was
+    /// already stored and referenced before the sync.
+    pub fn missing(&mut self, max: usize) -> Result<Vec<H256>, TrieError> {
+        while self.missing.len() < max {
+            let (hash, path) = match self.queue.pop() {
+                Some(request) => request,
+                None => break,
+            };
+            match self.db.get_value(&hash) {
+                Some(data) => {
+                    let node = match decode_node::<T>(&hash, &data[..]) {
+                        Ok(node) => node,
+                        Err(_) => return Err(TrieError::InvalidNode(hash, path)),
+                    };
+                    for (child, child_path) in children(&node, path) {
+                        self.schedule(child, child_path);
+                    }
+                },
+                None => self.missing.push((hash, path)),
+            }
+        }
+        if !self.complete && self.queue.is_empty() && self.missing.is_empty() {
+            if self.stored_root || self.db.ref_count(&self.root) == 0 {
+                self.db.insert_nodes(&Vec::new(), &self.root);
+            }
+            self.complete = true;
+        }
+        Ok(self.missing.iter().take(max).map(|&(hash, _)| hash).collect())
+    }
+
+    /// Stores the node with `hash` and schedules its children. Nodes that
+    /// weren't requested are ignored.
+    pub fn process(&mut self, hash: &H256, data: &[u8]) -> Result<(), TrieError> {
+        let position = match self.missing.iter().position(|&(missing, _)| missing == *hash) {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        if keccak(data) != *hash {
+            return Err(TrieError::HashMismatch(*hash, self.missing[position].1.clone()))
+        }
+        let node = match decode_node_strict::<T>(hash, data) {
+            Ok(node) => node,
+            Err(_) => return Err(TrieError::InvalidNode(*hash, self.missing[position].1.clone())),
+        };
+
+        self.db.store_nodes(&vec![(*hash, data.to_vec())]);
+        self.stored_root |= *hash == self.root;
+        let (_, path) = self.missing.remove(position);
+        for (child, child_path) in children(&node, path) {
+            self.schedule(child, child_path);
+        }
+        Ok(())
+    }
+
+    /// Whether every node of the tree is stored
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    fn schedule(&mut self, hash: H256, path: Vec<u8>) {
+        if self.seen.insert(hash) {
+            self.queue.push((hash, path));
+        }
+    }
+}
+
+// Hash references of the node at `path` with the paths of the nodes they
+// refer to, inlined children included
+fn children<T: Decodable>(node: &Node<T>, mut path: Vec<u8>) -> Vec<(H256, Vec<u8>)> {
+    let mut result = Vec::new();
+    collect_hashes(node, &mut path, &mut result);
+    result
+}
+
+fn collect_hashes<T: Decodable>(node: &Node<T>, path: &mut Vec<u8>, result: &mut Vec<(H256, Vec<u8>)>) {
+    match *node {
+        Node::FullNode {ref nibles, ..} => {
+            for (index, child) in nibles.iter().enumerate() {
+                if let Some(ref child) = *child {
+                    // the value at index 16 is at the node's own path
+                    if index < 16 {
+                        path.push(index as u8);
+                    }
+                    collect_hashes(child, path, result);
+                    if index < 16 {
+                        path.pop();
+                    }
+                }
+            }
+        },
+        Node::ShortNode {ref key, ref node, ..} => {
+            let length = path.len();
+            path.extend(key.as_slice().iter());
+            collect_hashes(node, path, result);
+            path.truncate(length);
+        },
+        Node::HashNode {ref hash} => {
+            result.push((*hash, path.clone()));
+        },
+        Node::ValueNode {..} | Node::Empty => {}
+    }
+}
+
+// What a hash stands for in a state sync
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum StateItem {
+    AccountNode,
+    StorageNode,
+    Code,
+}
+
+/// Downloads a whole Ethereum state like `TrieSync` does a single tree: the
+/// account trie, the storage trie of every account and the code of every
+/// contract.
+///
+/// Nodes and code are both requested by hash, as with Ethereum's node data
+/// requests, and `process` stores the data as whatever the hash was
+/// requested as. Once the sync is complete the state root and the storage
+/// roots are referenced like the roots of commits, except for roots that
+/// were already stored and referenced before the sync.
+pub struct StateSync {
+    db: Arc<Database>,
+    root: H256,
+    // paths are within the trie of the node, code has none
+    queue: Vec<(H256, StateItem, Vec<u8>)>,
+    missing: Vec<(H256, StateItem, Vec<u8>)>,
+    seen: HashSet<(H256, StateItem)>,
+    storage_roots: HashSet<H256>,
+    // roots whose node this sync stored
+    stored_roots: HashSet<H256>,
+    complete: bool,
+}
+
+impl StateSync {
+    pub fn new(db: Arc<Database>, root: H256) -> StateSync {
+        let mut sync = StateSync {
+            db,
+            root,
+            queue: Vec::new(),
+            missing: Vec::new(),
+            seen: HashSet::new(),
+            storage_roots: HashSet::new(),
+            stored_roots: HashSet::new(),
+            complete: root == EMPTY_ROOT,
+        };
+        if !sync.complete {
+            sync.schedule(root, StateItem::AccountNode, Vec::new());
+        }
+        sync
+    }
+
+    /// Up to `max` hashes of nodes and code to fetch, see `TrieSync::missing`
+    pub fn missing(&mut self, max: usize) -> Result<Vec<H256>, TrieError> {
+        while self.missing.len() < max {
+            let (hash, item, path) = match self.queue.pop() {
+                Some(request) => request,
+                None => break,
+            };
+            if item == StateItem::Code {
+                if self.db.get_code(&hash).is_none() {
+                    self.missing.push((hash, item, path));
+                }
+                continue;
+            }
+            match self.db.get_value(&hash) {
+                Some(data) => {
+                    let node = match decode_node::<Vec<u8>>(&hash, &data[..]) {
+                        Ok(node) => node,
+                        Err(_) => return Err(TrieError::InvalidNode(hash, path)),
+                    };
+                    self.schedule_children(&node, &data[..], item, path);
+                },
+                None => self.missing.push((hash, item, path)),
+            }
+        }
+        if !self.complete && self.queue.is_empty() && self.missing.is_empty() {
+            for root in self.storage_roots.iter().chain(Some(&self.root)) {
+                if self.stored_roots.contains(root) || self.db.ref_count(root) == 0 {
+                    self.db.insert_nodes(&Vec::new(), root);
+                }
+            }
+            self.complete = true;
+        }
+        Ok(self.missing.iter().take(max).map(|&(hash, _, _)| hash).collect())
+    }
+
+    /// Stores the node or code with `hash` and schedules what it refers to.
+    /// Data that wasn't requested is ignored.
+    pub fn process(&mut self, hash: &H256, data: &[u8]) -> Result<(), TrieError> {
+        let position = match self.missing.iter().position(|&(missing, _, _)| missing == *hash) {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        if keccak(data) != *hash {
+            return Err(TrieError::HashMismatch(*hash, self.missing[position].2.clone()))
+        }
+        let item = self.missing[position].1;
+        if item == StateItem::Code {
+            self.db.insert_code(data);
+            self.missing.remove(position);
+            return Ok(())
+        }
+        let node = match decode_node_strict::<Vec<u8>>(hash, data) {
+            Ok(node) => node,
+            Err(_) => return Err(TrieError::InvalidNode(*hash, self.missing[position].2.clone())),
+        };
+        self.db.store_nodes(&vec![(*hash, data.to_vec())]);
+        if *hash == self.root || self.storage_roots.contains(hash) {
+            self.stored_roots.insert(*hash);
+        }
+        let (_, _, path) = self.missing.remove(position);
+        self.schedule_children(&node, data, item, path);
+        Ok(())
+    }
+
+    /// Whether the whole state is stored
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    fn schedule(&mut self, hash: H256, item: StateItem, path: Vec<u8>) -> bool {
+        if self.seen.insert((hash, item)) {
+            self.queue.push((hash, item, path));
+            return true
+        }
+        false
+    }
+
+    // Child nodes of `node`, encoded as `data`, and for account nodes the
+    // storage roots and code of the accounts in its leaves
+    fn schedule_children(&mut self, node: &Node<Vec<u8>>, data: &[u8], item: StateItem, path: Vec<u8>) {
+        for (child, child_path) in children(node, path) {
+            self.schedule(child, item, child_path);
+        }
+        if item != StateItem::AccountNode {
+            return
+        }
+        for account in Account::from_node(data) {
+            if account.storage_root != EMPTY_ROOT {
+                // the root may already be scheduled as a node of another
+                // storage trie, it is still referenced as a root
+                self.schedule(account.storage_root, StateItem::StorageNode, Vec::new());
+                self.storage_roots.insert(account.storage_root);
+            }
+            if account.code_hash != KECCAK_EMPTY {
+                self.schedule(account.code_hash, StateItem::Code, Vec::new());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::MerkleTree;
+    use gc::{collect_garbage, collect_state_garbage};
+    use state::StateDB;
+    use ethereum_types::{Address, U256};
+    use rlp::RlpStream;
+
+    fn build_source() -> (Arc<Database>, H256) {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..300 {
+            tree.update(&H256::from(index * 0x10001u64), Some(index)).unwrap();
+        }
+        let root = tree.commit();
+        (db, root)
+    }
+
+    // Fetches at most `rounds` batches from `source`
+    fn sync_rounds(sync: &mut TrieSync<u64>, source: &Database, rounds: usize) {
+        for _ in 0..rounds {
+            let missing = sync.missing(16).unwrap();
+            if missing.is_empty() {
+                return
+            }
+            for hash in missing {
+                let data = source.get_value(&hash).unwrap();
+                sync.process(&hash, &data[..]).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn sync_test() {
+        let (source, root) = build_source();
+        let target = Database::in_memory();
+        let mut sync = TrieSync::<u64>::new(target.clone(), root);
+
+        assert!(MerkleTree::<u64>::at_root(target.clone(), root).is_err());
+        sync_rounds(&mut sync, &source, usize::MAX);
+        assert!(sync.is_complete());
+
+        let tree = MerkleTree::<u64>::at_root(target.clone(), root).unwrap();
+        for index in 0..300 {
+            assert_eq!(tree.get(&H256::from(index * 0x10001u64)).unwrap(), Some(index));
+        }
+        let mut nodes = 0;
+        target.for_each_node(|hash, data| {
+            assert_eq!(source.get_value(hash).unwrap(), data.to_vec());
+            assert_eq!(target.ref_count(hash), source.ref_count(hash));
+            nodes += 1;
+        });
+        assert_eq!(collect_garbage(&source, &[root]).nodes_kept, nodes);
+        // syncing a stored root again doesn't reference it again
+        let mut sync = TrieSync::<u64>::new(target.clone(), root);
+        assert!(sync.missing(16).unwrap().is_empty());
+        assert!(sync.is_complete());
+        assert_eq!(target.ref_count(&root), 1);
+        // the synced root can be pruned like a committed one
+        assert_eq!(target.prune_root(&root), nodes);
+    }
+
+    #[test]
+    fn resume_sync_test() {
+        let (source, root) = build_source();
+        let target = Database::in_memory();
+
+        let mut sync = TrieSync::<u64>::new(target.clone(), root);
+        sync_rounds(&mut sync, &source, 3);
+        assert!(!sync.is_complete());
+
+        let mut sync = TrieSync::<u64>::new(target.clone(), root);
+        sync_rounds(&mut sync, &source, usize::MAX);
+        assert!(sync.is_complete());
+        let tree = MerkleTree::<u64>::at_root(target.clone(), root).unwrap();
+        for index in 0..300 {
+            assert_eq!(tree.get(&H256::from(index * 0x10001u64)).unwrap(), Some(index));
+        }
+    }
+
+    #[test]
+    fn bad_node_test() {
+        let (source, root) = build_source();
+        let target = Database::in_memory();
+        let mut sync = TrieSync::<u64>::new(target.clone(), root);
+
+        assert_eq!(sync.missing(16).unwrap(), vec![root]);
+        let mut data = source.get_value(&root).unwrap();
+        data[5] ^= 1;
+        assert_eq!(sync.process(&root, &data[..]), Err(TrieError::HashMismatch(root, Vec::new())));
+        assert_eq!(target.get_value(&root), None);
+        // still missing after a bad response
+        assert_eq!(sync.missing(16).unwrap(), vec![root]);
+
+        // hashes right, but isn't a canonical node
+        let mut rlp_s = RlpStream::new_list(3);
+        rlp_s.append(&1u64).append(&2u64).append(&3u64);
+        let invalid = rlp_s.out();
+        let mut sync_invalid = TrieSync::<u64>::new(target.clone(), keccak(&invalid[..]));
+        sync_invalid.missing(16).unwrap();
+        assert_eq!(sync_invalid.process(&keccak(&invalid[..]), &invalid[..]), Err(TrieError::InvalidNode(keccak(&invalid[..]), Vec::new())));
+
+        let data = source.get_value(&root).unwrap();
+        sync.process(&root, &data[..]).unwrap();
+        assert!(!sync.missing(16).unwrap().contains(&root));
+
+        // errors tell where in the tree the node is
+        let child = sync.missing(16).unwrap()[0];
+        let mut data = source.get_value(&child).unwrap();
+        data[5] ^= 1;
+        match sync.process(&child, &data[..]) {
+            Err(TrieError::HashMismatch(hash, path)) => {
+                assert_eq!(hash, child);
+                assert!(!path.is_empty());
+            },
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    // Fetches nodes and code from `source` until the sync is complete
+    fn sync_state(sync: &mut StateSync, source: &Database) {
+        loop {
+            let missing = sync.missing(16).unwrap();
+            if missing.is_empty() {
+                return
+            }
+            for hash in missing {
+                let data = source.get_value(&hash).or_else(|| source.get_code(&hash)).unwrap();
+                sync.process(&hash, &data[..]).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn state_sync_test() {
+        let source = Database::in_memory();
+        let mut state = StateDB::new(source.clone(), EMPTY_ROOT).unwrap();
+
+        for index in 0..50 {
+            let address = Address::from(index);
+            state.set_balance(&address, U256::from(index)).unwrap();
+            if index % 5 == 0 {
+                state.set_code(&address, vec![index as u8; 40]).unwrap();
+                for slot in 0..index {
+                    state.set_storage(&address, &H256::from(slot), U256::from(slot + 1)).unwrap();
+                }
+            }
+        }
+        let root = state.commit().unwrap();
+        collect_state_garbage(&source, &[root]);
+
+        let target = Database::in_memory();
+        let mut sync = StateSync::new(target.clone(), root);
+        sync_state(&mut sync, &source);
+        assert!(sync.is_complete());
+
+        let state = StateDB::new(target.clone(), root).unwrap();
+        for index in 0..50 {
+            let address = Address::from(index);
+            assert_eq!(state.balance(&address).unwrap(), U256::from(index));
+            if index % 5 == 0 {
+                assert_eq!(state.get_code(&address).unwrap(), Some(vec![index as u8; 40]));
+                for slot in 0..index {
+                    assert_eq!(state.get_storage(&address, &H256::from(slot)).unwrap(), U256::from(slot + 1));
+                }
+            }
+        }
+        let mut nodes = 0;
+        target.for_each_node(|hash, data| {
+            assert_eq!(source.get_value(hash).unwrap(), data.to_vec());
+            nodes += 1;
+        });
+        let mut codes = 0;
+        target.for_each_code(|_, _| codes += 1);
+        // storage roots are held by the sync like by the commits
+        assert!(target.ref_count(&state.account(&Address::from(5)).unwrap().unwrap().storage_root) > 0);
+        let report = collect_state_garbage(&source, &[root]);
+        assert_eq!((report.nodes_kept, report.codes_kept), (nodes, codes));
+
+        // a new sync finds everything stored and leaves the counts alone
+        let mut counts = Vec::new();
+        target.for_each_node(|hash, _| counts.push((*hash, target.ref_count(hash))));
+        let mut sync = StateSync::new(target.clone(), root);
+        assert!(sync.missing(16).unwrap().is_empty());
+        assert!(sync.is_complete());
+        for (hash, count) in counts {
+            assert_eq!(target.ref_count(&hash), count);
+        }
+    }
+
+    #[test]
+    fn shared_storage_sync_test() {
+        let source = Database::in_memory();
+        let mut state = StateDB::new(source.clone(), EMPTY_ROOT).unwrap();
+        let alice = Address::from(1);
+        let bob = Address::from(2);
+        for slot in 0..10 {
+            state.set_storage(&alice, &H256::from(slot), U256::from(slot + 1)).unwrap();
+            state.set_storage(&bob, &H256::from(slot), U256::from(slot + 1)).unwrap();
+        }
+        let root = state.commit().unwrap();
+        let storage_root = state.account(&alice).unwrap().unwrap().storage_root;
+        assert_eq!(state.account(&bob).unwrap().unwrap().storage_root, storage_root);
+
+        let target = Database::in_memory();
+        let mut sync = StateSync::new(target.clone(), root);
+        sync_state(&mut sync, &source);
+        assert!(sync.is_complete());
+        assert!(sync.storage_roots.contains(&storage_root));
+        assert_eq!(target.ref_count(&storage_root), 1);
+
+        let state = StateDB::new(target.clone(), root).unwrap();
+        for slot in 0..10 {
+            assert_eq!(state.get_storage(&alice, &H256::from(slot)).unwrap(), U256::from(slot + 1));
+            assert_eq!(state.get_storage(&bob, &H256::from(slot)).unwrap(), U256::from(slot + 1));
+        }
+        let report = collect_state_garbage(&target, &[root]);
+        assert_eq!(report.nodes_deleted, 0);
+    }
+}
@@ -1,136 +1,1395 @@
 pub use rlp::{Encodable, Decodable};
 pub use std::clone::Clone;
 pub use ethereum_types::H256;
+pub use node::EMPTY_ROOT;
+pub use error::TrieError;
 use rlp;
+use rlp::{RlpStream, NULL_RLP};
 use node::*;
+use nibble::{NibbleSlice, NibbleVec};
+use hex_prefix;
 use db::*;
+use witness::Witness;
+use std::mem;
+use std::panic;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-//TODO: Results system, Tests for MerkleTree, Doc
+/// Merkle Patricia tree over a shared `Database`.
+///
+/// Changes made with `update` stay in memory until `commit` writes the new
+/// nodes in one atomic batch. Nodes are addressed by their hash, so writing
+/// them never disturbs trees opened at earlier roots: any number of readers
+/// (see `snapshot` and `at_root`) can run next to the single writer and only
+/// ever observe fully committed roots.
+///
+/// Nodes are shared between clones, so `clone` is O(1). A clone can be
+/// changed and committed on its own: `update` copies only the nodes on the
+/// path to the changed key, all other nodes stay shared.
+#[derive(Clone)]
 pub struct MerkleTree<T: Encodable + Decodable + Clone> {
-    root: Box<Node<T>>,
+    root: Arc<Node<T>>,
     hash: H256,
-    db: Box<Database>,
+    db: Arc<Database>,
+    // nodes read from the database, shared with clones
+    witness: Option<Recorder>,
 }
 
-impl<T: Encodable + Decodable + Clone> MerkleTree<T> {
-    pub fn new(hash: H256, db: Box<Database>) -> MerkleTree<T> {
-        let root;
-        if let Some(data) = db.get_value(&hash) {
-            let node_value = decode_node::<T>(&hash, &data[..]).unwrap();
-            root = Box::new(node_value);
+// Nodes recorded for a witness by hash
+type Recorder = Arc<Mutex<BTreeMap<H256, Vec<u8>>>>;
+
+// Where nodes are read from, every node read is added to the witness if
+// there is one. Also loads the nodes of `diff`.
+pub(crate) struct NodeSource<'a> {
+    db: &'a Database,
+    witness: Option<&'a Mutex<BTreeMap<H256, Vec<u8>>>>,
+}
+
+impl<'a> NodeSource<'a> {
+    pub(crate) fn new(db: &'a Database, witness: &'a Option<Recorder>) -> NodeSource<'a> {
+        NodeSource {
+            db,
+            witness: witness.as_ref().map(|witness| &**witness),
         }
-        else {
-            root = Box::new(Node::Empty)
+    }
+
+    fn get_value(&self, hash: &H256) -> Option<Vec<u8>> {
+        let data = self.db.get_value(hash);
+        if let (Some(witness), Some(data)) = (self.witness, data.as_ref()) {
+            witness.lock().unwrap().insert(*hash, data.clone());
+        }
+        data
+    }
+
+    // `path` is only used to report corrupt nodes
+    pub(crate) fn load_node<T: Decodable>(&self, hash: &H256, path: NibbleSlice) -> Result<Arc<Node<T>>, TrieError> {
+        self.load_encoded(hash, path).map(|(_, node)| node)
+    }
+
+    // Node with its encoding as stored
+    fn load_encoded<T: Decodable>(&self, hash: &H256, path: NibbleSlice) -> Result<(Vec<u8>, Arc<Node<T>>), TrieError> {
+        match self.get_value(hash) {
+            Some(ref data) if self.db.verify_hashes() && keccak(&data[..]) != *hash => {
+                Err(TrieError::HashMismatch(*hash, path.iter().collect()))
+            },
+            Some(data) => {
+                let node = if self.db.decode_strict() {
+                    decode_node_strict::<T>(hash, &data[..]).ok()
+                } else {
+                    decode_node::<T>(hash, &data[..]).ok()
+                };
+                match node {
+                    Some(node) => Ok((data, Arc::new(node))),
+                    None => Err(TrieError::InvalidNode(*hash, path.iter().collect())),
+                }
+            },
+            None => Err(TrieError::MissingNode(*hash)),
         }
+    }
+}
+
+impl<T: Encodable + Decodable + Clone> MerkleTree<T> {
+    /// Opens the tree at `hash`, a hash that isn't stored gives an empty tree.
+    /// A root node that is stored but corrupt is left unloaded, so the first
+    /// operation that needs it returns the error.
+    pub fn new(hash: H256, db: Arc<Database>) -> MerkleTree<T> {
+        let root = match NodeSource::new(&db, &None).load_node(&hash, NibbleSlice::new(&[])) {
+            Ok(root) => root,
+            Err(TrieError::MissingNode(_)) => Arc::new(Node::Empty),
+            Err(_) => Arc::new(Node::HashNode {hash}),
+        };
         MerkleTree {
             root,
             hash,
             db,
+            witness: None,
         }
     }
 
-    pub fn update(&mut self, key: &H256, value: Option<T>) {
-        let key_path = Self::key_bytes_to_hex(key);
+    /// Opens any previously committed root for reading. Committed roots stay
+    /// readable until their nodes are deleted from the database, in which case
+    /// `MissingNode` is returned here or by later lookups.
+    pub fn at_root(db: Arc<Database>, root: H256) -> Result<MerkleTree<T>, TrieError> {
+        Self::open(db, root, None)
+    }
+
+    /// Opens the tree at `root` like `at_root` and records every node that
+    /// `get`, `update` and the other operations read from the database, the
+    /// root included. `witness` returns the nodes read so far.
+    pub fn recording(db: Arc<Database>, root: H256) -> Result<MerkleTree<T>, TrieError> {
+        Self::open(db, root, Some(Arc::new(Mutex::new(BTreeMap::new()))))
+    }
+
+    /// Opens the tree at `root` with only the nodes of `witness`, e.g. to
+    /// repeat the operations of a recording tree without the database. The
+    /// nodes are kept in memory together with what is committed later. An
+    /// operation that needs a node the witness lacks fails with
+    /// `MissingNode`, one that reaches a malformed node with `InvalidNode`.
+    pub fn from_witness(root: H256, witness: &Witness) -> Result<MerkleTree<T>, TrieError> {
+        let db = Database::in_memory();
+        db.set_decode_strict(true);
+        db.store_nodes(&witness.nodes().iter().map(|(hash, data)| (*hash, data.clone())).collect());
+        Self::at_root(db, root)
+    }
+
+    fn open(db: Arc<Database>, root: H256, witness: Option<Recorder>) -> Result<MerkleTree<T>, TrieError> {
+        let root_node = if root == EMPTY_ROOT {
+            Arc::new(Node::Empty)
+        } else {
+            NodeSource::new(&db, &witness).load_node(&root, NibbleSlice::new(&[]))?
+        };
+        Ok(MerkleTree {
+            root: root_node,
+            hash: root,
+            db,
+            witness,
+        })
+    }
+
+    /// Nodes read since the tree was opened by `recording`, empty for other
+    /// trees. Clones record into the same witness.
+    pub fn witness(&self) -> Witness {
+        match self.witness {
+            Some(ref witness) => Witness::from_nodes(witness.lock().unwrap().values().cloned().collect()),
+            None => Witness::default(),
+        }
+    }
+
+    /// Opens the tree at the root stored as `LATEST_ROOT`, an empty tree if
+    /// there is none
+    pub fn open_latest(db: Arc<Database>) -> Result<MerkleTree<T>, TrieError> {
+        let root = db.root(LATEST_ROOT).unwrap_or(EMPTY_ROOT);
+        Self::at_root(db, root)
+    }
+
+    /// Hash of the last committed root
+    pub fn root_hash(&self) -> H256 {
+        self.hash
+    }
+
+    /// Read-only tree at the last committed root, sharing the database.
+    /// Uncommitted changes of this tree are not visible through it.
+    pub fn snapshot(&self) -> MerkleTree<T> {
+        MerkleTree::new(self.hash, self.db.clone())
+    }
+
+    /// Sets the value of `key`, `None` removes the key. On error the tree
+    /// is left as it was before the call.
+    pub fn update(&mut self, key: &H256, value: Option<T>) -> Result<(), TrieError> {
+        let key_path = NibbleSlice::new(key);
+        let source = NodeSource::new(&self.db, &self.witness);
 
         if let Some(value) = value {
-            Self::insert_helper(&self.db, &key_path[..], &mut self.root, Box::new(Node::ValueNode{value}));
+            Self::insert_helper(&source, key_path, key_path, &mut self.root, value)?;
         }
         else {
-            Self::delete_helper(&self.db, &key_path[..], &mut self.root);
+            Self::delete_helper(&source, key_path, key_path, &mut self.root)?;
+        }
+        Ok(())
+    }
+
+    /// Hashes the changed nodes, stores them and returns the new root hash.
+    /// Every commit holds a reference on its root until it is released with
+    /// `Database::prune_root`.
+    pub fn commit(&mut self) -> H256 {
+        self.commit_with_undo().0
+    }
+
+    /// Commits and stores the new root under `name`, e.g. `LATEST_ROOT`
+    pub fn commit_as(&mut self, name: &str) -> H256 {
+        let root = self.commit();
+        self.db.set_root(name, &root);
+        root
+    }
+
+    /// Same as `commit`, also returns what is needed to undo the commit
+    pub fn commit_with_undo(&mut self) -> (H256, UndoLog) {
+        self.write_commit(Vec::new())
+    }
+
+    /// Hashes the changed nodes like `commit` without storing them, for
+    /// commits that are written together with others. Returns the new root
+    /// hash and the nodes to store, children before their parents.
+    pub(crate) fn hash_changes(&mut self) -> (H256, Vec<(H256, Vec<u8>)>) {
+        let mut batch = Vec::new();
+        let root = self.hash_nodes(&mut batch);
+        (root, batch)
+    }
+
+    // Hashes the nodes that are still dirty and writes them along with
+    // `batch`, nodes that were already hashed
+    fn write_commit(&mut self, mut batch: Vec<(H256, Vec<u8>)>) -> (H256, UndoLog) {
+        let root = self.hash_nodes(&mut batch);
+        if root == EMPTY_ROOT {
+            return (root, UndoLog::default())
         }
-        //Recalculate hash
+        // nothing is visible to readers until the whole batch is written
+        let undo = self.db.insert_nodes(&batch, &root);
+        (root, undo)
     }
 
-    pub fn get(&mut self, key: &H256) -> Option<T> {
-        let key_path = Self::key_bytes_to_hex(key);
-        Self::get_helper(&self.db, &key_path[..], &mut self.root)
+    // Hashes the dirty nodes into `batch` and returns the root hash
+    fn hash_nodes(&mut self, batch: &mut Vec<(H256, Vec<u8>)>) -> H256 {
+        if let Node::Empty = *self.root {
+            self.hash = EMPTY_ROOT;
+            return self.hash
+        }
+        let root_ref = Self::commit_helper(&mut self.root, batch, true);
+        self.hash = rlp::decode(&root_ref[..]);
+        self.hash
+    }
+
+    /// Applies many changes at once. The changes are sorted by key and
+    /// applied in a single descent, so keys with a common prefix share the
+    /// work on their common path. The result is the same as calling `update`
+    /// for each change in order. On error the tree is left as it was.
+    pub fn update_batch<I>(&mut self, changes: I) -> Result<(), TrieError>
+    where
+        I: IntoIterator<Item = (H256, Option<T>)>,
+    {
+        let mut changes: Vec<_> = changes.into_iter().collect();
+        // stable sort, so the last change of a key is the one that is kept
+        changes.sort_by_key(|a| a.0);
+        let mut unique: Vec<(H256, Option<T>)> = Vec::with_capacity(changes.len());
+
+        for change in changes {
+            if unique.last().map(|last| last.0) == Some(change.0) {
+                unique.pop();
+            }
+            unique.push(change);
+        }
+        if unique.is_empty() {
+            return Ok(())
+        }
+        // changed nodes are copied anyway, the root is only replaced once
+        // everything is loaded
+        let mut root = self.root.clone();
+        Self::batch_helper(&self.source(), &unique[..], 0, &mut root)?;
+        self.root = root;
+        Ok(())
     }
 
-    fn get_helper(db: &Database, key_path: &[u8], node: &mut Box<Node<T>>) -> Option<T> {
-        let mut loaded_node;
+    /// Iterates over the keys and values in key order, uncommitted changes
+    /// included. Stored nodes are loaded as they are reached, an error ends
+    /// the iteration.
+    pub fn iter(&self) -> TreeIterator<T> {
+        TreeIterator {
+            db: self.db.clone(),
+            witness: self.witness.clone(),
+            stack: vec![(self.root.clone(), NibbleVec::new())],
+        }
+    }
 
-        match node.as_mut() {
-            &mut Node::FullNode {ref mut nibles, ref flags} => {
+    pub fn get(&self, key: &H256) -> Result<Option<T>, TrieError> {
+        let key_path = NibbleSlice::new(key);
+        Self::get_helper(&self.source(), key_path, key_path, &self.root)
+    }
+
+    /// Encoded nodes on the path to `key` in the last committed root, root
+    /// first. The proof shows the value of the key or that it isn't set, see
+    /// `proof::verify_proof`. Uncommitted changes are not included.
+    pub fn get_proof(&self, key: &H256) -> Result<Vec<Vec<u8>>, TrieError> {
+        let key_path = NibbleSlice::new(key);
+        let mut proof = Vec::new();
+        let mut hash = self.hash;
+        let mut consumed = 0;
+
+        if hash == EMPTY_ROOT {
+            return Ok(proof)
+        }
+        let source = self.source();
+        loop {
+            let (data, node) = source.load_encoded::<T>(&hash, key_path.split(consumed).0)?;
+            proof.push(data);
+            // inlined children are part of their parent's encoding
+            match Self::next_hash(&node, key_path.mid(consumed)) {
+                Some((next, nibbles)) => {
+                    hash = next;
+                    consumed += nibbles;
+                },
+                None => return Ok(proof),
+            }
+        }
+    }
+
+    // Next stored node on the path and the number of nibbles to it, `None`
+    // if the path ends in this node
+    fn next_hash(node: &Node<T>, key_path: NibbleSlice) -> Option<(H256, usize)> {
+        match *node {
+            Node::FullNode {ref nibles, ..} => {
                 if key_path.is_empty() {
-                    if let Some(ref mut node) = nibles[16] {
-                        return Self::get_helper(db, key_path, node)
-                    }
                     return None
                 }
-                if let Some(ref mut node) = nibles[key_path[0] as usize] {
-                    return Self::get_helper(db, &key_path[1..], node)
+                match nibles[key_path.at(0) as usize] {
+                    Some(ref child) => Self::next_hash(child, key_path.mid(1)).map(|(hash, nibbles)| (hash, nibbles + 1)),
+                    None => None,
                 }
-                return None
             },
-            &mut Node::ShortNode {ref key, ref mut node, ref flags} => {
-                if key_path.len() >= key.len() && key[..] == key_path[..key.len()] {
-                    return Self::get_helper(db, &key_path[key.len()..], node)
+            Node::ShortNode {ref key, ref node, ..} => {
+                if !key_path.starts_with(&key.as_slice()) {
+                    return None
                 }
-                return None
+                Self::next_hash(node, key_path.mid(key.len())).map(|(hash, nibbles)| (hash, nibbles + key.len()))
             },
-            &mut Node::HashNode {ref hash} => {
-                if let Some(data) = db.get_value(hash) {
-                    let node_value = decode_node::<T>(hash, &data[..]).unwrap();
-                    loaded_node = Box::new(node_value);
+            Node::HashNode {hash} => Some((hash, 0)),
+            Node::ValueNode {..} | Node::Empty => None,
+        }
+    }
+
+    // Lookups never modify the tree: nodes behind a HashNode are decoded into
+    // a temporary, so a tree can be shared between threads for reading.
+    // `key_path` is the part of `full_path` that is left to match.
+    fn get_helper(db: &NodeSource, full_path: NibbleSlice, key_path: NibbleSlice, node: &Node<T>) -> Result<Option<T>, TrieError> {
+        match *node {
+            Node::FullNode {ref nibles, ..} => {
+                if key_path.is_empty() {
+                    if let Some(ref node) = nibles[16] {
+                        return Self::get_helper(db, full_path, key_path, node)
+                    }
+                    return Ok(None)
                 }
-                else {
-                    loaded_node = Box::new(Node::Empty)
+                if let Some(ref node) = nibles[key_path.at(0) as usize] {
+                    return Self::get_helper(db, full_path, key_path.mid(1), node)
                 }
+                Ok(None)
             },
-            &mut Node::ValueNode {ref value} => {
+            Node::ShortNode {ref key, ref node, ..} => {
+                if key_path.starts_with(&key.as_slice()) {
+                    return Self::get_helper(db, full_path, key_path.mid(key.len()), node)
+                }
+                Ok(None)
+            },
+            Node::HashNode {ref hash} => {
+                let loaded_node = db.load_node(hash, Self::consumed(full_path, key_path))?;
+                Self::get_helper(db, full_path, key_path, &loaded_node)
+            },
+            Node::ValueNode {ref value} => {
                 if key_path.is_empty() {
-                    return Some(value.clone())
+                    return Ok(Some(value.clone()))
                 }
-                return None
+                Ok(None)
             },
-            &mut Node::Empty => {
-                return None
+            Node::Empty => {
+                Ok(None)
             }
         }
+    }
+
+    // Replaces a HashNode at `path` with the node it refers to
+    fn resolve(db: &NodeSource, path: NibbleSlice, node: &mut Arc<Node<T>>) -> Result<(), TrieError> {
+        let loaded_node = match **node {
+            Node::HashNode {ref hash} => db.load_node(hash, path)?,
+            _ => return Ok(()),
+        };
         *node = loaded_node;
-        Self::get_helper(db, &key_path[..], node)
+        Ok(())
     }
 
-    fn insert_helper(db: &Database, key_path: &[u8], node: &mut Box<Node<T>>, value_node: Box<Node<T>>) -> (bool, Box<Node<T>>) {
-        match node.as_mut() {
-            &mut Node::FullNode {ref mut nibles, ref flags} => {
-                (false, Box::new(Node::Empty))
+    // Nodes are only changed after everything they depend on has been
+    // loaded, so an error leaves the subtree unchanged. Nodes shared with a
+    // clone are only copied once a change below them is known, see
+    // `change_child`. Returns whether the subtree changed.
+    fn insert_helper(db: &NodeSource, full_path: NibbleSlice, key_path: NibbleSlice, node: &mut Arc<Node<T>>, value: T) -> Result<bool, TrieError> {
+        Self::resolve(db, Self::consumed(full_path, key_path), node)?;
+
+        let new_node = match **node {
+            // the key ends at a value, leaves keep their short node
+            Node::ValueNode {value: ref old} => {
+                if rlp::encode(old)[..] == rlp::encode(&value)[..] {
+                    return Ok(false)
+                }
+                Arc::new(Node::ValueNode {value})
+            },
+            Node::FullNode {..} => {
+                let index = key_path.at(0) as usize;
+                return Self::change_child(node, index, |child| Self::insert_helper(db, full_path, key_path.mid(1), child, value))
             },
-            &mut Node::ShortNode {ref key, ref mut node, ref flags} => {
-                let (dirty, loaded_node) = Self::insert_helper(db, &key_path[..], node, value_node);
-                if dirty {
-                    *node = loaded_node;
+            Node::ShortNode {ref key, node: ref child, ..} => {
+                let match_len = key.as_slice().common_prefix(&key_path);
+
+                if match_len == key.len() {
+                    return Self::change_child(node, 0, |child| Self::insert_helper(db, full_path, key_path.mid(match_len), child, value))
                 }
-                (false, Box::new(Node::Empty))
+                // paths diverge inside the key: split it with a full node
+                let mut nibles = Self::empty_nibles();
+                nibles[key.at(match_len) as usize] = Some(Self::new_short(key.as_slice().mid(match_len + 1), child.clone()));
+                nibles[key_path.at(match_len) as usize] = Some(Self::new_leaf(key_path.mid(match_len + 1), value));
+                let branch = Arc::new(Node::FullNode {nibles, flags: NodeFlag::new_dirty()});
+
+                Self::new_short(key_path.split(match_len).0, branch)
+            },
+            Node::Empty => {
+                Self::new_leaf(key_path, value)
+            },
+            Node::HashNode {..} => {
+                panic!("Invalid node");
+            }
+        };
+        *node = new_node;
+        Ok(true)
+    }
+
+    // Changes the child of a full node by nibble `index`, or the child of a
+    // short node, with `change`, which returns whether it changed anything. A
+    // missing child is passed as an empty node and an emptied one is removed.
+    // A node that isn't shared is changed in place, a shared one is copied
+    // only if its child changed. Changed nodes are marked dirty.
+    fn change_child<F>(node: &mut Arc<Node<T>>, index: usize, change: F) -> Result<bool, TrieError>
+    where
+        F: FnOnce(&mut Arc<Node<T>>) -> Result<bool, TrieError>,
+    {
+        let shared = Arc::get_mut(node).is_none();
+        let mut child = if shared {
+            Self::child(node, index)
+        } else {
+            // taken out so that it isn't shared either
+            Self::replace_child(Arc::get_mut(node).unwrap(), index, Arc::new(Node::Empty))
+        };
+        let changed = change(&mut child);
+        let dirty = changed == Ok(true);
+        if shared && !dirty {
+            return changed
+        }
+        let node = Arc::make_mut(node);
+        Self::replace_child(node, index, child);
+        if dirty {
+            match *node {
+                Node::FullNode {ref mut flags, ..} | Node::ShortNode {ref mut flags, ..} => *flags = NodeFlag::new_dirty(),
+                _ => {}
+            }
+        }
+        changed
+    }
+
+    // Child of `node` like in `change_child`
+    fn child(node: &Node<T>, index: usize) -> Arc<Node<T>> {
+        match *node {
+            Node::FullNode {ref nibles, ..} => nibles[index].clone().unwrap_or_else(|| Arc::new(Node::Empty)),
+            Node::ShortNode {ref node, ..} => node.clone(),
+            _ => panic!("Invalid node"),
+        }
+    }
+
+    // Puts `child` where `child` finds the child of `node`, returns the old
+    // one
+    fn replace_child(node: &mut Node<T>, index: usize, child: Arc<Node<T>>) -> Arc<Node<T>> {
+        match *node {
+            Node::FullNode {ref mut nibles, ..} => {
+                let is_empty = matches!(*child, Node::Empty);
+                let child = if is_empty { None } else { Some(child) };
+                mem::replace(&mut nibles[index], child).unwrap_or_else(|| Arc::new(Node::Empty))
             },
-            &mut Node::HashNode {ref hash} => {
-                // tree not loaded
-                if let Some(data) = db.get_value(hash) {
-                    let loaded_node = decode_node::<T>(hash, &data[..]).unwrap();
-                    return (true, Box::new(loaded_node))
+            Node::ShortNode {ref mut node, ..} => mem::replace(node, child),
+            _ => panic!("Invalid node"),
+        }
+    }
+
+    // `changes` are sorted by key, unique and share the first `depth` nibbles,
+    // the path of `node`. Returns whether the subtree changed.
+    fn batch_helper(db: &NodeSource, changes: &[(H256, Option<T>)], depth: usize, node: &mut Arc<Node<T>>) -> Result<bool, TrieError> {
+        if changes.len() == 1 {
+            let full_path = NibbleSlice::new(&changes[0].0);
+
+            return match changes[0].1 {
+                Some(ref value) => Self::insert_helper(db, full_path, full_path.mid(depth), node, value.clone()),
+                None => Self::delete_helper(db, full_path, full_path.mid(depth), node),
+            }
+        }
+        let path = NibbleSlice::new(&changes[0].0).split(depth).0;
+        Self::resolve(db, path, node)?;
+
+        // keys are sorted, so all of them start with a prefix if the first
+        // and the last one do
+        let first = NibbleSlice::new(&changes[0].0).mid(depth);
+        let last = NibbleSlice::new(&changes[changes.len() - 1].0).mid(depth);
+
+        // the keys differ further down, so from here on every node is treated
+        // as a full node and turned back into the canonical form at the end
+        let mut nibles = match **node {
+            Node::FullNode {ref nibles, ..} => nibles.clone(),
+            Node::ShortNode {ref key, node: ref child, ..} => {
+                if first.starts_with(&key.as_slice()) && last.starts_with(&key.as_slice()) {
+                    let mut child = child.clone();
+                    if !Self::batch_helper(db, changes, depth + key.len(), &mut child)? {
+                        return Ok(false)
+                    }
+                    let mut short_key = key.clone();
+                    let joined = match *child {
+                        Node::Empty => child.clone(),
+                        Node::ShortNode {key: ref child_key, node: ref child_node, ..} => {
+                            short_key.extend(&child_key.as_slice());
+                            Arc::new(Node::ShortNode {key: short_key, node: child_node.clone(), flags: NodeFlag::new_dirty()})
+                        },
+                        _ => Arc::new(Node::ShortNode {key: short_key, node: child.clone(), flags: NodeFlag::new_dirty()}),
+                    };
+                    *node = joined;
+                    return Ok(true)
                 }
-                (false, Box::new(Node::Empty))
+                let mut nibles = Self::empty_nibles();
+                nibles[key.at(0) as usize] = Some(Self::new_short(key.as_slice().mid(1), child.clone()));
+                nibles
             },
-            &mut Node::Empty => {
-                (false, Box::new(Node::Empty))
+            Node::Empty => Self::empty_nibles(),
+            Node::HashNode {..} | Node::ValueNode {..} => {
+                panic!("Invalid node");
+            }
+        };
+        let mut dirty = false;
+        let mut start = 0;
+
+        while start < changes.len() {
+            let index = NibbleSlice::new(&changes[start].0).at(depth) as usize;
+            let end = start + changes[start..].iter()
+                .take_while(|change| NibbleSlice::new(&change.0).at(depth) as usize == index)
+                .count();
+            let mut child = nibles[index].take().unwrap_or_else(|| Arc::new(Node::Empty));
+
+            dirty |= Self::batch_helper(db, &changes[start..end], depth + 1, &mut child)?;
+            if !matches!(*child, Node::Empty) {
+                nibles[index] = Some(child);
+            }
+            start = end;
+        }
+        if !dirty {
+            return Ok(false)
+        }
+        let used: Vec<usize> = (0..17).filter(|&i| nibles[i].is_some()).collect();
+
+        *node = match used.len() {
+            0 => Arc::new(Node::Empty),
+            1 => {
+                let mut child = nibles[used[0]].take().unwrap();
+                if used[0] != 16 {
+                    let mut child_path = NibbleVec::from(path);
+                    child_path.push(used[0] as u8);
+                    Self::resolve(db, child_path.as_slice(), &mut child)?;
+                }
+                Self::single_child(used[0], child)
             },
-            _ => {
+            _ => Arc::new(Node::FullNode {nibles, flags: NodeFlag::new_dirty()}),
+        };
+        Ok(true)
+    }
+
+    // Same guarantees as insert_helper. Returns whether the subtree changed.
+    fn delete_helper(db: &NodeSource, full_path: NibbleSlice, key_path: NibbleSlice, node: &mut Arc<Node<T>>) -> Result<bool, TrieError> {
+        Self::resolve(db, Self::consumed(full_path, key_path), node)?;
+
+        let new_node = match **node {
+            Node::FullNode {ref nibles, ..} => {
+                if key_path.is_empty() {
+                    return Ok(false)
+                }
+                let index = key_path.at(0) as usize;
+                if nibles[index].is_none() {
+                    return Ok(false)
+                }
+                let siblings: Vec<usize> = (0..17).filter(|&i| i != index && nibles[i].is_some()).collect();
+
+                // the node collapses into its only sibling if the child goes
+                // away, load the sibling before anything is changed
+                let mut sibling = None;
+                if siblings.len() == 1 {
+                    let mut child = nibles[siblings[0]].clone().unwrap();
+                    if siblings[0] != 16 {
+                        let mut path = NibbleVec::from(Self::consumed(full_path, key_path));
+                        path.push(siblings[0] as u8);
+                        Self::resolve(db, path.as_slice(), &mut child)?;
+                    }
+                    sibling = Some(child);
+                }
+                if !Self::change_child(node, index, |child| Self::delete_helper(db, full_path, key_path.mid(1), child))? {
+                    return Ok(false)
+                }
+                let removed = match **node {
+                    Node::FullNode {ref nibles, ..} => nibles[index].is_none(),
+                    _ => false,
+                };
+                match sibling {
+                    // a full node with a single child is replaced by a short node
+                    Some(sibling) if removed => Self::single_child(siblings[0], sibling),
+                    _ => return Ok(true),
+                }
+            },
+            Node::ShortNode {ref key, ..} => {
+                if !key_path.starts_with(&key.as_slice()) {
+                    return Ok(false)
+                }
+                if key.len() == key_path.len() {
+                    Arc::new(Node::Empty)
+                } else {
+                    let key_len = key.len();
+                    if !Self::change_child(node, 0, |child| Self::delete_helper(db, full_path, key_path.mid(key_len), child))? {
+                        return Ok(false)
+                    }
+                    // the changed node is no longer shared
+                    if let Node::ShortNode {ref mut key, ref mut node, ..} = *Arc::make_mut(node) {
+                        let child = mem::replace(node, Arc::new(Node::Empty));
+
+                        match *child {
+                            // merge with the short node that replaced the child
+                            Node::ShortNode {key: ref child_key, node: ref child_node, ..} => {
+                                key.extend(&child_key.as_slice());
+                                *node = child_node.clone();
+                            },
+                            Node::Empty => {},
+                            _ => {
+                                *node = child.clone();
+                            }
+                        }
+                    }
+                    return Ok(true)
+                }
+            },
+            Node::ValueNode {..} => {
+                Arc::new(Node::Empty)
+            },
+            Node::Empty => {
+                return Ok(false)
+            },
+            Node::HashNode {..} => {
                 panic!("Invalid node");
             }
+        };
+        *node = new_node;
+        Ok(true)
+    }
+
+    // Short node replacing a full node whose only child is `child` at `position`,
+    // a short child is merged into it
+    fn single_child(position: usize, child: Arc<Node<T>>) -> Arc<Node<T>> {
+        let mut short_key = NibbleVec::new();
+        // the value of the full node becomes a leaf with an empty key
+        if position != 16 {
+            short_key.push(position as u8);
+        }
+        match *child {
+            Node::ShortNode {ref key, ref node, ..} => {
+                short_key.extend(&key.as_slice());
+                Self::new_short(short_key.as_slice(), node.clone())
+            },
+            _ => {
+                Self::new_short(short_key.as_slice(), child.clone())
+            }
+        }
+    }
+
+    fn new_leaf(key_path: NibbleSlice, value: T) -> Arc<Node<T>> {
+        Self::new_short(key_path, Arc::new(Node::ValueNode {value}))
+    }
+
+    // Leaves are short nodes even when the key is empty
+    fn new_short(key: NibbleSlice, node: Arc<Node<T>>) -> Arc<Node<T>> {
+        let is_value = matches!(*node, Node::ValueNode {..});
+        if key.is_empty() && !is_value {
+            return node
+        }
+        Arc::new(Node::ShortNode {key: NibbleVec::from(key), node, flags: NodeFlag::new_dirty()})
+    }
+
+    // Encodes dirty nodes bottom up. Every node whose encoding is at least 32
+    // bytes long, and the root, goes to the batch; the return value is the rlp
+    // the parent embeds: the node hash or the whole short encoding.
+    fn commit_helper(node: &mut Arc<Node<T>>, batch: &mut Vec<(H256, Vec<u8>)>, force: bool) -> Vec<u8> {
+        // stored nodes that didn't change are referenced without copying them
+        match **node {
+            Node::FullNode {ref flags, ..} | Node::ShortNode {ref flags, ..} => {
+                if !flags.dirty && !flags.hash.is_zero() {
+                    return rlp::encode(&flags.hash).to_vec()
+                }
+            },
+            Node::HashNode {ref hash} => {
+                return rlp::encode(hash).to_vec()
+            },
+            Node::ValueNode {ref value} => {
+                return rlp::encode(value).to_vec()
+            },
+            Node::Empty => {
+                return NULL_RLP.to_vec()
+            }
+        }
+        let data = match *Arc::make_mut(node) {
+            Node::FullNode {ref mut nibles, ..} => {
+                let mut rlp_s = RlpStream::new_list(17);
+
+                for nible in nibles.iter_mut() {
+                    match *nible {
+                        Some(ref mut child) => {
+                            let child_ref = Self::commit_helper(child, batch, false);
+                            rlp_s.append_raw(&child_ref[..], 1);
+                        },
+                        None => {
+                            rlp_s.append_empty_data();
+                        }
+                    }
+                }
+                rlp_s.out()
+            },
+            Node::ShortNode {ref key, ref mut node, ..} => {
+                let is_leaf = matches!(**node, Node::ValueNode {..});
+                let mut rlp_s = RlpStream::new_list(2);
+                rlp_s.append(&hex_prefix::encode(&key.as_slice(), is_leaf));
+                let child_ref = Self::commit_helper(node, batch, false);
+                rlp_s.append_raw(&child_ref[..], 1);
+                rlp_s.out()
+            },
+            _ => unreachable!()
+        };
+        let mut flags = NodeFlag {hash: H256::zero(), dirty: false};
+        let node_ref = if data.len() >= 32 || force {
+            let hash = keccak(&data[..]);
+            let hash_ref = rlp::encode(&hash).to_vec();
+            flags.hash = hash;
+            batch.push((hash, data));
+            hash_ref
+        } else {
+            data
+        };
+        match *Arc::make_mut(node) {
+            Node::FullNode {flags: ref mut node_flags, ..} |
+            Node::ShortNode {flags: ref mut node_flags, ..} => *node_flags = flags,
+            _ => {}
+        }
+        node_ref
+    }
+
+    fn source<'a>(&'a self) -> NodeSource<'a> {
+        NodeSource::new(&self.db, &self.witness)
+    }
+
+    fn is_dirty(node: &Node<T>) -> bool {
+        match *node {
+            Node::FullNode {ref flags, ..} | Node::ShortNode {ref flags, ..} => flags.dirty,
+            _ => false,
+        }
+    }
+
+    // Part of `full_path` in front of the remaining `key_path`
+    fn consumed<'a>(full_path: NibbleSlice<'a>, key_path: NibbleSlice) -> NibbleSlice<'a> {
+        full_path.split(full_path.len() - key_path.len()).0
+    }
+
+    fn empty_nibles() -> [Option<Arc<Node<T>>>; 17] {
+        [None, None, None, None, None, None, None, None, None,
+            None, None, None, None, None, None, None, None]
+    }
+}
+
+impl<T: Encodable + Decodable + Clone + Send + Sync + 'static> MerkleTree<T> {
+    /// Same as `commit`, but if the root is a full node its dirty children
+    /// are hashed and encoded on up to `threads` threads. The root and the
+    /// stored nodes are the same as with `commit`. A panic on one of the
+    /// threads is raised again, with the tree left as it was.
+    pub fn commit_parallel(&mut self, threads: usize) -> H256 {
+        self.commit_parallel_with_undo(threads).0
+    }
+
+    /// Same as `commit_parallel`, also returns what is needed to undo the commit
+    pub fn commit_parallel_with_undo(&mut self, threads: usize) -> (H256, UndoLog) {
+        let mut batch = Vec::new();
+
+        if threads > 1 && Self::is_dirty(&self.root) {
+            if let Node::FullNode {ref mut nibles, ..} = *Arc::make_mut(&mut self.root) {
+                // the threads hash copies of the children and hand them back,
+                // so a panic on one of them leaves the tree as it was
+                let mut work = vec![Vec::new(); threads];
+                let mut next = 0;
+
+                for (index, child) in nibles.iter().enumerate().take(16) {
+                    if let Some(ref child) = *child {
+                        if Self::is_dirty(child) {
+                            work[next % threads].push((index, child.clone()));
+                            next += 1;
+                        }
+                    }
+                }
+                let workers: Vec<_> = work.into_iter().filter(|children| !children.is_empty()).map(|mut children| {
+                    thread::spawn(move || {
+                        let mut batch = Vec::new();
+                        for &mut (_, ref mut child) in children.iter_mut() {
+                            Self::commit_helper(child, &mut batch, false);
+                        }
+                        (children, batch)
+                    })
+                }).collect();
+
+                let results: Vec<_> = workers.into_iter().map(|worker| worker.join()).collect();
+                let mut hashed = Vec::new();
+                for result in results {
+                    match result {
+                        Ok(result) => hashed.push(result),
+                        Err(payload) => panic::resume_unwind(payload),
+                    }
+                }
+                for (children, nodes) in hashed {
+                    for (index, child) in children {
+                        nibles[index] = Some(child);
+                    }
+                    batch.extend(nodes);
+                }
+            }
+        }
+        // children that were hashed are referenced by their hash, small
+        // ones are encoded again into the root
+        self.write_commit(batch)
+    }
+}
+
+/// Iterator over the keys and values of a tree, see `MerkleTree::iter`
+pub struct TreeIterator<T: Encodable + Decodable + Clone> {
+    db: Arc<Database>,
+    witness: Option<Recorder>,
+    // nodes left to visit with their paths, the next one on top
+    stack: Vec<(Arc<Node<T>>, NibbleVec)>,
+}
+
+impl<T: Encodable + Decodable + Clone> Iterator for TreeIterator<T> {
+    type Item = Result<(H256, T), TrieError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, path)) = self.stack.pop() {
+            match *node {
+                Node::FullNode {ref nibles, ..} => {
+                    for index in (0..17).rev() {
+                        if let Some(ref child) = nibles[index] {
+                            let mut child_path = path.clone();
+                            if index < 16 {
+                                child_path.push(index as u8);
+                            }
+                            self.stack.push((child.clone(), child_path));
+                        }
+                    }
+                },
+                Node::ShortNode {ref key, ref node, ..} => {
+                    let mut child_path = path.clone();
+                    child_path.extend(&key.as_slice());
+                    self.stack.push((node.clone(), child_path));
+                },
+                Node::HashNode {ref hash} => {
+                    match NodeSource::new(&self.db, &self.witness).load_node(hash, path.as_slice()) {
+                        Ok(loaded_node) => self.stack.push((loaded_node, path)),
+                        Err(error) => {
+                            self.stack.clear();
+                            return Some(Err(error))
+                        },
+                    }
+                },
+                Node::ValueNode {ref value} => {
+                    let mut key = [0u8; 32];
+                    for index in 0..path.len() {
+                        key[index / 2] |= path.at(index) << if index % 2 == 0 { 4 } else { 0 };
+                    }
+                    return Some(Ok((H256::from(key), value.clone())))
+                },
+                Node::Empty => {},
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlp::{RlpStream, UntrustedRlp, DecoderError};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn test_key(first: u8, last: u8) -> H256 {
+        let mut key = H256::zero();
+        key[0] = first;
+        key[31] = last;
+        key
+    }
+
+    // Root full node with two leaves stored behind hash references
+    fn build_test_db() -> Arc<Database> {
+        let db = Database::in_memory();
+        // nodes are stored under made up hashes
+        db.set_verify_hashes(false);
+        let keys = [test_key(0x12, 0x01), test_key(0x34, 0x02)];
+        let values: [u64; 2] = [11, 22];
+        let mut refs = [None, None, None, None, None, None, None, None,
+            None, None, None, None, None, None, None, None];
+
+        for index in 0..2 {
+            // leaf keeps 63 nibbles: odd length leaf flag in the high nibble
+            let mut leaf_key = keys[index].to_vec();
+            leaf_key[0] = 0x30 | (leaf_key[0] & 0x0F);
+            let mut rlp_s = RlpStream::new_list(2);
+            rlp_s.append(&leaf_key).append(&values[index]);
+
+            let leaf_hash = H256::from(index as u64 + 1);
+            db.set_value(&leaf_hash, &rlp_s.out());
+            refs[(keys[index][0] >> 4) as usize] = Some(leaf_hash);
+        }
+        let mut rlp_s = RlpStream::new_list(17);
+        for reference in refs.iter() {
+            match *reference {
+                Some(ref hash) => { rlp_s.append(&hash.to_vec()); },
+                None => { rlp_s.append_empty_data(); },
+            }
+        }
+        rlp_s.append_empty_data();
+        db.set_value(&H256::from(0x100u64), &rlp_s.out());
+        db
+    }
+
+    #[test]
+    fn get_does_not_require_mut_test() {
+        let db = build_test_db();
+        let tree = MerkleTree::<u64>::new(H256::from(0x100u64), db);
+
+        assert_eq!(tree.get(&test_key(0x12, 0x01)).unwrap(), Some(11));
+        assert_eq!(tree.get(&test_key(0x34, 0x02)).unwrap(), Some(22));
+        assert_eq!(tree.get(&test_key(0x12, 0x02)).unwrap(), None);
+        assert_eq!(tree.get(&test_key(0x56, 0x01)).unwrap(), None);
+        // repeated lookups resolve the same hash nodes again
+        assert_eq!(tree.get(&test_key(0x12, 0x01)).unwrap(), Some(11));
+    }
+
+    #[test]
+    fn concurrent_get_test() {
+        let db = build_test_db();
+        let tree = Arc::new(MerkleTree::<u64>::new(H256::from(0x100u64), db));
+
+        let handles: Vec<_> = (0..4).map(|_| {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    assert_eq!(tree.get(&test_key(0x12, 0x01)).unwrap(), Some(11));
+                    assert_eq!(tree.get(&test_key(0x34, 0x02)).unwrap(), Some(22));
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            assert!(handle.join().is_ok());
+        }
+    }
+
+    #[test]
+    fn update_commit_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..100 {
+            tree.update(&H256::from(index * 7919u64), Some(index)).unwrap();
+        }
+        assert_eq!(tree.get(&H256::from(7919u64)).unwrap(), Some(1));
+        let root = tree.commit();
+        assert_eq!(tree.root_hash(), root);
+
+        let tree = MerkleTree::<u64>::new(root, db);
+        for index in 0..100 {
+            assert_eq!(tree.get(&H256::from(index * 7919u64)).unwrap(), Some(index));
+        }
+        assert_eq!(tree.get(&H256::from(1u64)).unwrap(), None);
+    }
+
+    #[test]
+    fn root_independent_of_order_test() {
+        let db = Database::in_memory();
+        let mut forward = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let mut backward = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..50 {
+            forward.update(&test_key(index as u8 * 5, index as u8), Some(index)).unwrap();
+            backward.update(&test_key((49 - index) as u8 * 5, (49 - index) as u8), Some(49 - index)).unwrap();
+        }
+        assert_eq!(forward.commit(), backward.commit());
+        // overwriting with the same value changes nothing
+        forward.update(&test_key(0, 0), Some(0)).unwrap();
+        assert_eq!(forward.commit(), backward.root_hash());
+    }
+
+    #[test]
+    fn delete_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        tree.update(&test_key(0x12, 1), Some(1)).unwrap();
+        tree.update(&test_key(0x13, 2), Some(2)).unwrap();
+        let root = tree.commit();
+
+        tree.update(&test_key(0x12, 3), Some(3)).unwrap();
+        tree.update(&test_key(0x45, 4), Some(4)).unwrap();
+        tree.commit();
+        tree.update(&test_key(0x12, 3), None).unwrap();
+        tree.update(&test_key(0x45, 4), None).unwrap();
+        // deleting a missing key leaves the tree as it is
+        tree.update(&test_key(0x77, 4), None).unwrap();
+        assert_eq!(tree.commit(), root);
+
+        // reload so that deletes have to resolve stored nodes
+        let mut tree = MerkleTree::<u64>::new(root, db);
+        tree.update(&test_key(0x12, 1), None).unwrap();
+        assert_eq!(tree.get(&test_key(0x13, 2)).unwrap(), Some(2));
+        tree.update(&test_key(0x13, 2), None).unwrap();
+        assert_eq!(tree.commit(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn open_latest_test() {
+        let db = Database::in_memory();
+        let first = {
+            let mut tree = MerkleTree::<u64>::open_latest(db.clone()).unwrap();
+            assert_eq!(tree.root_hash(), EMPTY_ROOT);
+
+            tree.update(&test_key(0x10, 0), Some(1)).unwrap();
+            let first = tree.commit_as(LATEST_ROOT);
+            db.set_root(FINALIZED_ROOT, &first);
+            tree.update(&test_key(0x10, 0), Some(2)).unwrap();
+            tree.commit_as(LATEST_ROOT);
+            first
+        };
+        // reopened without knowing the roots
+        let tree = MerkleTree::<u64>::open_latest(db.clone()).unwrap();
+        assert_eq!(tree.get(&test_key(0x10, 0)).unwrap(), Some(2));
+        assert_eq!(db.root(FINALIZED_ROOT), Some(first));
+
+        db.prune_root(&tree.root_hash());
+        assert!(MerkleTree::<u64>::open_latest(db.clone()).is_err());
+    }
+
+    #[test]
+    fn iter_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let mut expected = Vec::new();
+
+        assert_eq!(tree.iter().count(), 0);
+        for index in 0..300 {
+            let key = H256::from(index * 0x1234567u64);
+            tree.update(&key, Some(index)).unwrap();
+            expected.push((key, index));
+        }
+        expected.sort();
+        tree.commit();
+        tree.update(&expected[5].0, None).unwrap();
+        tree.update(&expected[6].0, Some(1000)).unwrap();
+        let stored = expected.clone();
+        expected.remove(5);
+        expected[5].1 = 1000;
+
+        // uncommitted changes and nodes loaded from the database
+        let items: Result<Vec<_>, _> = tree.iter().collect();
+        assert_eq!(items.unwrap(), expected);
+        let snapshot = tree.snapshot();
+        let items: Result<Vec<_>, _> = snapshot.iter().collect();
+        assert_eq!(items.unwrap(), stored);
+
+        // the second child of the first node with more than one, so some
+        // values come before it
+        let mut children = child_hashes(&db.get_value(&snapshot.root_hash()).unwrap()[..]);
+        while children.len() < 2 {
+            children = child_hashes(&db.get_value(&children[0]).unwrap()[..]);
+        }
+        let child = children[1];
+        db.delete_value(&child);
+        let mut iter = snapshot.iter();
+        assert!(iter.by_ref().take_while(|item| item.is_ok()).count() > 0);
+        assert!(iter.next().is_none());
+        assert_eq!(snapshot.iter().find(|item| item.is_err()), Some(Err(TrieError::MissingNode(child))));
+    }
+
+    #[test]
+    fn historical_root_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let mut roots = Vec::new();
+
+        for round in 0..5 {
+            tree.update(&test_key(0x10, 0), Some(round)).unwrap();
+            tree.update(&test_key(0x20, round as u8), Some(round)).unwrap();
+            roots.push(tree.commit());
+        }
+        for round in 0..5 {
+            let old = MerkleTree::<u64>::at_root(db.clone(), roots[round as usize]).unwrap();
+            assert_eq!(old.root_hash(), roots[round as usize]);
+            assert_eq!(old.get(&test_key(0x10, 0)).unwrap(), Some(round));
+            assert_eq!(old.get(&test_key(0x20, round as u8)).unwrap(), Some(round));
+            assert_eq!(old.get(&test_key(0x20, round as u8 + 1)).unwrap(), None);
+        }
+        let empty = MerkleTree::<u64>::at_root(db, EMPTY_ROOT).unwrap();
+        assert_eq!(empty.get(&test_key(0x10, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn missing_node_test() {
+        let db = build_test_db();
+        let unknown = H256::from(42u64);
+
+        match MerkleTree::<u64>::at_root(db.clone(), unknown) {
+            Err(error) => assert_eq!(error, TrieError::MissingNode(unknown)),
+            Ok(_) => panic!("opened a tree at an unknown root"),
+        }
+        let mut tree = MerkleTree::<u64>::at_root(db.clone(), H256::from(0x100u64)).unwrap();
+        // prune the second leaf
+        db.delete_value(&H256::from(2u64));
+
+        assert_eq!(tree.get(&test_key(0x34, 0x02)), Err(TrieError::MissingNode(H256::from(2u64))));
+        assert_eq!(tree.update(&test_key(0x34, 0x03), Some(1)), Err(TrieError::MissingNode(H256::from(2u64))));
+        assert_eq!(tree.update(&test_key(0x34, 0x02), None), Err(TrieError::MissingNode(H256::from(2u64))));
+        // a failed update leaves the tree untouched
+        assert_eq!(tree.commit(), H256::from(0x100u64));
+        assert_eq!(tree.get(&test_key(0x12, 0x01)).unwrap(), Some(11));
+    }
+
+    #[test]
+    fn corrupt_node_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let key = test_key(0x12, 0);
+
+        tree.update(&key, Some(1)).unwrap();
+        for index in 0..16 {
+            tree.update(&test_key(0x20 + index, 0), Some(index as u64)).unwrap();
+        }
+        let root = tree.commit();
+        // leaf of the key under nibble 1 of the root, its value is the
+        // last byte
+        let child = child_hashes(&db.get_value(&root).unwrap()[..])[0];
+        let mut data = db.get_value(&child).unwrap();
+        let last = data.len() - 1;
+        data[last] = 3;
+        db.set_value(&child, &data);
+
+        db.set_verify_hashes(true);
+        let tree = MerkleTree::<u64>::at_root(db.clone(), root).unwrap();
+        assert_eq!(tree.get(&key), Err(TrieError::HashMismatch(child, vec![1])));
+        assert_eq!(tree.get_proof(&key), Err(TrieError::HashMismatch(child, vec![1])));
+        let mut writer = tree.clone();
+        assert_eq!(writer.update(&key, None), Err(TrieError::HashMismatch(child, vec![1])));
+
+        // without the check the corrupt value is read back
+        db.set_verify_hashes(false);
+        assert_eq!(tree.get(&key).unwrap(), Some(3));
+
+        // a corrupt root is reported by the operations, not by `new`
+        db.set_verify_hashes(true);
+        let mut data = db.get_value(&root).unwrap();
+        data[3] ^= 1;
+        db.set_value(&root, &data);
+        let mut tree = MerkleTree::<u64>::new(root, db.clone());
+        assert_eq!(tree.get(&key), Err(TrieError::HashMismatch(root, Vec::new())));
+        assert_eq!(tree.update(&key, Some(2)), Err(TrieError::HashMismatch(root, Vec::new())));
+        assert!(tree.iter().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn clone_shares_nodes_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..16 {
+            tree.update(&test_key(index << 4, 0), Some(index as u64)).unwrap();
+        }
+        let root = tree.commit();
+        let mut fork = tree.clone();
+        assert!(Arc::ptr_eq(&tree.root, &fork.root));
+
+        // updates that change nothing copy nothing
+        fork.update(&test_key(0x10, 0), Some(1)).unwrap();
+        fork.update(&test_key(0x11, 0), None).unwrap();
+        fork.update(&test_key(0x10, 1), None).unwrap();
+        assert!(Arc::ptr_eq(&tree.root, &fork.root));
+
+        fork.update(&test_key(0x10, 0), Some(100)).unwrap();
+        assert!(!Arc::ptr_eq(&tree.root, &fork.root));
+        // only the path to the changed key is copied
+        match (&*tree.root, &*fork.root) {
+            (Node::FullNode {nibles: old, ..}, Node::FullNode {nibles: new, ..}) => {
+                for index in 0..16 {
+                    let shared = Arc::ptr_eq(old[index].as_ref().unwrap(), new[index].as_ref().unwrap());
+                    assert_eq!(shared, index != 1);
+                }
+            },
+            _ => panic!("root is not a full node"),
+        }
+        let fork_root = fork.commit();
+        assert!(fork_root != root);
+        assert_eq!(tree.get(&test_key(0x10, 0)).unwrap(), Some(1));
+        assert_eq!(fork.get(&test_key(0x10, 0)).unwrap(), Some(100));
+
+        // the original still commits on its own
+        tree.update(&test_key(0x20, 0), None).unwrap();
+        tree.commit();
+        assert_eq!(MerkleTree::<u64>::at_root(db.clone(), fork_root).unwrap().get(&test_key(0x20, 0)).unwrap(), Some(2));
+        assert_eq!(tree.get(&test_key(0x20, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn commit_parallel_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..1000 {
+            tree.update(&keccak(&[(index % 256) as u8, (index / 256) as u8]), Some(index)).unwrap();
+        }
+        for round in 0..2 {
+            let mut sequential = tree.clone();
+            let root = tree.commit_parallel(3);
+            let mut nodes = 0;
+            db.for_each_node(|_, _| nodes += 1);
+
+            // the sequential commit finds every node already stored
+            assert_eq!(sequential.commit(), root);
+            let mut after = 0;
+            db.for_each_node(|_, _| after += 1);
+            assert_eq!(after, nodes);
+
+            // only some children of the root are dirty the second time
+            for index in 0..3 {
+                tree.update(&keccak(&[index as u8, 0]), Some(round + 2000)).unwrap();
+            }
         }
+        assert_eq!(tree.get(&keccak(&[7, 3])).unwrap(), Some(7 + 3 * 256));
     }
 
-    fn delete_helper(db: &Database, key_path: &[u8], node: &mut Box<Node<T>>) {
+    // Value whose encoding panics for 13
+    #[derive(Clone, Debug, PartialEq)]
+    struct Fragile(u64);
 
+    impl Encodable for Fragile {
+        fn rlp_append(&self, s: &mut RlpStream) {
+            assert!(self.0 != 13, "can't encode 13");
+            s.append(&self.0);
+        }
     }
 
-    fn key_bytes_to_hex(key: &H256) -> Vec<u8> {
-        let mut result = Vec::new();
+    impl Decodable for Fragile {
+        fn decode(rlp: &UntrustedRlp) -> Result<Fragile, DecoderError> {
+            rlp.as_val().map(Fragile)
+        }
+    }
+
+    #[test]
+    fn commit_parallel_panic_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<Fragile>::new(EMPTY_ROOT, db.clone());
+
+        for index in 0..200 {
+            tree.update(&keccak(&[index as u8]), Some(Fragile(index))).unwrap();
+        }
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| tree.commit_parallel(3)));
+        assert!(result.is_err());
+        db.for_each_node(|_, _| panic!("a failed commit wrote nodes"));
 
-        for iter in 0..key.len() {
-            result.push((key[iter] & 0xF0) >> 4);
-            result.push(key[iter] & 0x0F);
+        // nothing was lost or half committed
+        for index in 0..200 {
+            assert_eq!(tree.get(&keccak(&[index as u8])).unwrap(), Some(Fragile(index)));
+        }
+        // replacing the value would encode the old one
+        tree.update(&keccak(&[13]), None).unwrap();
+        tree.update(&keccak(&[13]), Some(Fragile(1013))).unwrap();
+        let root = tree.commit_parallel(3);
+        let tree = MerkleTree::<Fragile>::at_root(db.clone(), root).unwrap();
+        for index in 0..200 {
+            let value = if index == 13 { 1013 } else { index };
+            assert_eq!(tree.get(&keccak(&[index as u8])).unwrap(), Some(Fragile(value)));
         }
-        result
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn update_batch_test() {
+        let db = Database::in_memory();
+        let mut batched = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        let mut sequential = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        // random keys and keys with long common prefixes
+        let keys: Vec<H256> = (0..300).map(|index| {
+            if index % 2 == 0 {
+                keccak(&[index as u8, (index >> 8) as u8])
+            } else {
+                test_key((index % 7) as u8, index as u8)
+            }
+        }).collect();
+
+        for round in 0..4 {
+            let mut changes = Vec::new();
+            for (index, key) in keys.iter().enumerate() {
+                match (index + round) % 4 {
+                    0 => changes.push((*key, None)),
+                    1 => {},
+                    _ => changes.push((*key, Some((index * round) as u64))),
+                }
+            }
+            // repeated keys, the last change wins
+            changes.push((keys[5], Some(7)));
+            changes.push((keys[5], None));
+
+            for (key, value) in changes.iter() {
+                sequential.update(key, *value).unwrap();
+            }
+            batched.update_batch(changes).unwrap();
+            assert_eq!(batched.commit(), sequential.commit());
+        }
+        for key in keys.iter() {
+            assert_eq!(batched.get(key).unwrap(), sequential.get(key).unwrap());
+        }
+        // deleting everything gives the empty root
+        batched.update_batch(keys.iter().map(|key| (*key, None))).unwrap();
+        assert_eq!(batched.commit(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn update_batch_missing_node_test() {
+        let db = build_test_db();
+        let mut tree = MerkleTree::<u64>::at_root(db.clone(), H256::from(0x100u64)).unwrap();
+        db.delete_value(&H256::from(0x2u64));
+
+        let changes = vec![(test_key(0x12, 0x05), Some(5)), (test_key(0x34, 0x03), Some(3))];
+        assert_eq!(tree.update_batch(changes), Err(TrieError::MissingNode(H256::from(0x2u64))));
+        // the first change isn't applied either
+        assert_eq!(tree.get(&test_key(0x12, 0x05)).unwrap(), None);
+        assert_eq!(tree.get(&test_key(0x12, 0x01)).unwrap(), Some(11));
+    }
+
+    #[test]
+    fn send_sync_test() {
+        fn assert_send_sync<S: Send + Sync>() {}
+        assert_send_sync::<Database>();
+        assert_send_sync::<MerkleTree<u64>>();
+    }
+
+    #[test]
+    fn readers_see_only_committed_roots_test() {
+        use std::sync::RwLock;
+
+        let db = Database::in_memory();
+        let mut writer = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        for index in 0..64 {
+            writer.update(&H256::from(index as u64), Some(0)).unwrap();
+        }
+        let latest = Arc::new(RwLock::new(writer.commit()));
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let db = db.clone();
+            let latest = latest.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let root = *latest.read().unwrap();
+                    let tree = MerkleTree::<u64>::new(root, db.clone());
+                    let round = tree.get(&H256::from(0u64)).unwrap();
+                    // every key of a committed root holds the same round
+                    for index in 1..64 {
+                        assert_eq!(tree.get(&H256::from(index as u64)).unwrap(), round);
+                    }
+                }
+            })
+        }).collect();
+
+        for round in 1..20 {
+            for index in 0..64 {
+                writer.update(&H256::from(index as u64), Some(round)).unwrap();
+            }
+            let root = writer.commit();
+            *latest.write().unwrap() = root;
+            assert_eq!(writer.snapshot().get(&H256::from(5u64)).unwrap(), Some(round));
+        }
+        for reader in readers {
+            assert!(reader.join().is_ok());
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use ethereum_types::H256;
+use rlp::{Encodable, Decodable, RlpStream, UntrustedRlp, DecoderError};
+use node::keccak;
+use std::collections::BTreeMap;
+
+/// Nodes needed to repeat operations on a tree without its database, see
+/// `MerkleTree::recording` and `MerkleTree::from_witness`. Every node is
+/// kept once, keyed by its hash. Encoded as an rlp list of the nodes, so it
+/// can be sent to wherever the operations are repeated.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Witness {
+    nodes: BTreeMap<H256, Vec<u8>>,
+}
+
+impl Witness {
+    /// Witness of the rlp encoded `nodes`, duplicates are dropped
+    pub fn from_nodes(nodes: Vec<Vec<u8>>) -> Witness {
+        Witness {
+            nodes: nodes.into_iter().map(|data| (keccak(&data), data)).collect(),
+        }
+    }
+
+    pub fn nodes(&self) -> &BTreeMap<H256, Vec<u8>> {
+        &self.nodes
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+impl Encodable for Witness {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(self.nodes.len());
+        for data in self.nodes.values() {
+            s.append(data);
+        }
+    }
+}
+
+impl Decodable for Witness {
+    fn decode(rlp: &UntrustedRlp) -> Result<Witness, DecoderError> {
+        Ok(Witness::from_nodes(rlp.as_list()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::MerkleTree;
+    use db::Database;
+    use error::TrieError;
+    use node::EMPTY_ROOT;
+    use rlp;
+
+    fn key(index: u64) -> H256 {
+        keccak(&H256::from(index))
+    }
+
+    #[test]
+    fn witness_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        for index in 0..200 {
+            tree.update(&key(index), Some(index)).unwrap();
+        }
+        let root = tree.commit();
+
+        let mut recording = MerkleTree::<u64>::recording(db.clone(), root).unwrap();
+        assert_eq!(recording.get(&key(3)).unwrap(), Some(3));
+        assert_eq!(recording.get(&key(500)).unwrap(), None);
+        recording.update(&key(7), Some(70)).unwrap();
+        recording.update(&key(300), Some(300)).unwrap();
+        recording.update(&key(11), None).unwrap();
+        let new_root = recording.commit();
+        let witness = recording.witness();
+        assert!(!witness.is_empty());
+        let mut stored = 0;
+        db.for_each_node(|_, _| stored += 1);
+        assert!(witness.len() < stored);
+        for (hash, data) in witness.nodes() {
+            assert_eq!(db.get_value(hash).as_ref(), Some(data));
+        }
+
+        let mut stateless = MerkleTree::<u64>::from_witness(root, &witness).unwrap();
+        assert_eq!(stateless.get(&key(3)).unwrap(), Some(3));
+        assert_eq!(stateless.get(&key(500)).unwrap(), None);
+        stateless.update(&key(7), Some(70)).unwrap();
+        stateless.update(&key(300), Some(300)).unwrap();
+        stateless.update(&key(11), None).unwrap();
+        assert_eq!(stateless.commit(), new_root);
+
+        // keys off the recorded paths need nodes the witness lacks
+        let mut stateless = MerkleTree::<u64>::from_witness(root, &witness).unwrap();
+        let missing = (0..200).find(|&index| stateless.get(&key(index)).is_err()).unwrap();
+        match stateless.get(&key(missing)) {
+            Err(TrieError::MissingNode(_)) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+        assert!(stateless.update(&key(missing), Some(0)).is_err());
+
+        assert!(MerkleTree::<u64>::from_witness(root, &Witness::default()).is_err());
+        assert!(MerkleTree::<u64>::new(root, db.clone()).witness().is_empty());
+    }
+
+    #[test]
+    fn collapse_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<Vec<u8>>::new(EMPTY_ROOT, db.clone());
+        tree.update(&H256::from(0x1000u64), Some(vec![1; 40])).unwrap();
+        tree.update(&H256::from(0x2000u64), Some(vec![2; 40])).unwrap();
+        let root = tree.commit();
+
+        // deleting one of two leaves reads the sibling to collapse the branch
+        let mut recording = MerkleTree::<Vec<u8>>::recording(db.clone(), root).unwrap();
+        recording.update(&H256::from(0x1000u64), None).unwrap();
+        let new_root = recording.commit();
+
+        let mut stateless = MerkleTree::<Vec<u8>>::from_witness(root, &recording.witness()).unwrap();
+        stateless.update(&H256::from(0x1000u64), None).unwrap();
+        assert_eq!(stateless.commit(), new_root);
+        assert_eq!(stateless.get(&H256::from(0x2000u64)).unwrap(), Some(vec![2; 40]));
+    }
+
+    #[test]
+    fn proof_witness_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        for index in 0..200 {
+            tree.update(&key(index), Some(index)).unwrap();
+        }
+        let root = tree.commit();
+
+        // the proof nodes are recorded like the nodes of a lookup
+        let recording = MerkleTree::<u64>::recording(db.clone(), root).unwrap();
+        let proof = recording.get_proof(&key(5)).unwrap();
+        assert_eq!(recording.witness(), Witness::from_nodes(proof));
+    }
+
+    #[test]
+    fn from_nodes_test() {
+        let nodes = vec![vec![0xc2, 0x01, 0x02], vec![0xc1, 0x03], vec![0xc2, 0x01, 0x02]];
+        let witness = Witness::from_nodes(nodes);
+        assert_eq!(witness.len(), 2);
+        assert_eq!(witness.nodes().get(&keccak(&[0xc1, 0x03])), Some(&vec![0xc1, 0x03]));
+        assert_eq!(Witness::from_nodes(witness.nodes().values().cloned().collect()), witness);
+    }
+
+    #[test]
+    fn rlp_test() {
+        let db = Database::in_memory();
+        let mut tree = MerkleTree::<u64>::new(EMPTY_ROOT, db.clone());
+        for index in 0..50 {
+            tree.update(&key(index), Some(index)).unwrap();
+        }
+        let root = tree.commit();
+        let mut recording = MerkleTree::<u64>::recording(db.clone(), root).unwrap();
+        recording.update(&key(1), Some(10)).unwrap();
+        let new_root = recording.commit();
+
+        let encoded = rlp::encode(&recording.witness());
+        let witness: Witness = rlp::decode(&encoded[..]);
+        assert_eq!(witness, recording.witness());
+        let mut stateless = MerkleTree::<u64>::from_witness(root, &witness).unwrap();
+        stateless.update(&key(1), Some(10)).unwrap();
+        assert_eq!(stateless.commit(), new_root);
+
+        assert_eq!(Witness::decode(&UntrustedRlp::new(&[0xc1, 0xc0])), Err(DecoderError::RlpExpectedToBeData));
+    }
+
+    #[test]
+    fn malformed_witness_test() {
+        // a leaf with a trailing byte, which only the strict decoder sees
+        let mut invalid = RlpStream::new_list(2);
+        invalid.append(&vec![0x20u8; 32]).append(&1u64);
+        let mut invalid = invalid.out();
+        invalid.push(0x00);
+        let root = keccak(&invalid);
+        let witness = Witness::from_nodes(vec![invalid]);
+        assert_eq!(MerkleTree::<u64>::from_witness(root, &witness).err(), Some(TrieError::InvalidNode(root, Vec::new())));
+
+        // a leaf whose value isn't a u64, below a valid branch
+        let mut leaf = RlpStream::new_list(2);
+        leaf.append(&vec![0x20u8; 32]).append(&vec![7u8; 40]);
+        let leaf = leaf.out();
+        let leaf_hash = keccak(&leaf);
+        let mut branch = RlpStream::new_list(17);
+        for index in 0..17 {
+            match index {
+                1 | 2 => { branch.append(&leaf_hash); },
+                _ => { branch.append_empty_data(); },
+            }
+        }
+        let branch = branch.out();
+        let witness = Witness::from_nodes(vec![branch.clone(), leaf]);
+        let tree = MerkleTree::<u64>::from_witness(keccak(&branch), &witness).unwrap();
+        let mut key = H256::zero();
+        key[0] = 0x10;
+        assert_eq!(tree.get(&key), Err(TrieError::InvalidNode(leaf_hash, vec![1])));
+    }
+}
@@ -15,11 +15,11 @@
 #![feature(test)]
 
 extern crate test;
-extern crate ethcore_bigint as bigint;
+extern crate ethereum_types as bigint;
 extern crate rlp;
 
 use test::Bencher;
-use bigint::prelude::U256;
+use bigint::U256;
 use rlp::{RlpStream, Rlp};
 
 #[bench]